@@ -0,0 +1,185 @@
+// Bit-level operations on byte-vector bitmaps. Offsets count from the most
+// significant bit of byte 0, as in Redis. Serialized as "BITS" followed by
+// the bytes in hex, since WAL values must be valid UTF-8.
+
+use serde::{Deserialize, Serialize};
+
+const HEADER: &str = "BITS";
+
+// Highest bit offset SETBIT accepts (512 MiB of bitmap, as in Redis)
+pub const MAX_BIT_OFFSET: u64 = (1 << 32) - 1;
+
+// Set or clear one bit, growing the bitmap as needed; returns the old bit
+pub fn set_bit(bytes: &mut Vec<u8>, offset: u64, bit: bool) -> u8 {
+    let index = (offset / 8) as usize;
+    let mask = 0x80u8 >> (offset % 8);
+    if index >= bytes.len() {
+        bytes.resize(index + 1, 0);
+    }
+
+    let old = (bytes[index] & mask != 0) as u8;
+    if bit {
+        bytes[index] |= mask;
+    } else {
+        bytes[index] &= !mask;
+    }
+    old
+}
+
+// Read one bit; bits past the end are 0
+pub fn get_bit(bytes: &[u8], offset: u64) -> u8 {
+    let index = (offset / 8) as usize;
+    let mask = 0x80u8 >> (offset % 8);
+    bytes.get(index).map_or(0, |b| (b & mask != 0) as u8)
+}
+
+// Count set bits in the inclusive byte range [start, end]; negative
+// indexes count from the end and out-of-range indexes are clamped
+pub fn bit_count(bytes: &[u8], start: i64, end: i64) -> u64 {
+    let len = bytes.len() as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let end = if end < 0 { len + end } else { end.min(len - 1) };
+    if start > end || start >= len {
+        return 0;
+    }
+
+    bytes[start as usize..=end as usize]
+        .iter()
+        .map(|b| b.count_ones() as u64)
+        .sum()
+}
+
+pub fn encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(HEADER.len() + bytes.len() * 2);
+    out.push_str(HEADER);
+    for b in bytes {
+        out.push_str(&format!("{:02x}", b));
+    }
+    out
+}
+
+// Parse an encoded bitmap; None if the string is not one
+pub fn decode(s: &str) -> Option<Vec<u8>> {
+    let hex = s.strip_prefix(HEADER)?;
+    // from_str_radix alone would also accept a '+' sign
+    if hex.is_empty() || hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    (0..hex.len() / 2)
+        .map(|i| u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok())
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BitOp {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+// Combine bitmaps byte-wise. Shorter inputs are zero-padded to the longest,
+// so the result is as long as the longest source.
+pub fn bit_op(op: BitOp, sources: &[&[u8]]) -> Vec<u8> {
+    let len = sources.iter().map(|s| s.len()).max().unwrap_or(0);
+    let byte_at = |source: &[u8], i: usize| source.get(i).copied().unwrap_or(0);
+
+    (0..len)
+        .map(|i| match op {
+            BitOp::Not => !byte_at(sources[0], i),
+            BitOp::And => sources.iter().fold(0xff, |acc, s| acc & byte_at(s, i)),
+            BitOp::Or => sources.iter().fold(0, |acc, s| acc | byte_at(s, i)),
+            BitOp::Xor => sources.iter().fold(0, |acc, s| acc ^ byte_at(s, i)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_bit_grows_the_buffer() {
+        let mut bytes = Vec::new();
+        assert_eq!(set_bit(&mut bytes, 0, true), 0);
+        assert_eq!(bytes, vec![0x80]);
+
+        assert_eq!(set_bit(&mut bytes, 23, true), 0);
+        assert_eq!(bytes, vec![0x80, 0x00, 0x01]);
+
+        // Clearing a bit past the end still grows the bitmap
+        assert_eq!(set_bit(&mut bytes, 39, false), 0);
+        assert_eq!(bytes.len(), 5);
+    }
+
+    #[test]
+    fn set_bit_returns_the_old_bit() {
+        let mut bytes = Vec::new();
+        set_bit(&mut bytes, 7, true);
+        assert_eq!(set_bit(&mut bytes, 7, true), 1);
+        assert_eq!(set_bit(&mut bytes, 7, false), 1);
+        assert_eq!(set_bit(&mut bytes, 7, false), 0);
+        assert_eq!(bytes, vec![0x00]);
+    }
+
+    #[test]
+    fn get_bit_past_the_end_is_zero() {
+        let bytes = vec![0xff];
+        assert_eq!(get_bit(&bytes, 7), 1);
+        assert_eq!(get_bit(&bytes, 8), 0);
+        assert_eq!(get_bit(&bytes, MAX_BIT_OFFSET), 0);
+        assert_eq!(get_bit(&[], 0), 0);
+    }
+
+    #[test]
+    fn bit_count_with_negative_start_and_end() {
+        // 1, 2, 3 and 4 bits set
+        let bytes = vec![0x01, 0x03, 0x07, 0x0f];
+        assert_eq!(bit_count(&bytes, 0, -1), 10);
+        assert_eq!(bit_count(&bytes, -2, -1), 7);
+        assert_eq!(bit_count(&bytes, 1, -2), 5);
+        assert_eq!(bit_count(&bytes, -1, -1), 4);
+        // A start before the beginning is clamped to 0
+        assert_eq!(bit_count(&bytes, -100, -4), 1);
+        // An end before the beginning, or start after end, counts nothing
+        assert_eq!(bit_count(&bytes, 0, -100), 0);
+        assert_eq!(bit_count(&bytes, -1, -2), 0);
+        // An end past the end is clamped
+        assert_eq!(bit_count(&bytes, 2, 100), 7);
+        assert_eq!(bit_count(&bytes, 4, 100), 0);
+        assert_eq!(bit_count(&[], 0, -1), 0);
+    }
+
+    #[test]
+    fn bit_op_pads_shorter_operands_with_zeros() {
+        let long: &[u8] = &[0xff, 0xf0, 0x0f];
+        let short: &[u8] = &[0x3c];
+
+        assert_eq!(bit_op(BitOp::And, &[long, short]), vec![0x3c, 0x00, 0x00]);
+        assert_eq!(bit_op(BitOp::Or, &[short, long]), vec![0xff, 0xf0, 0x0f]);
+        assert_eq!(bit_op(BitOp::Xor, &[long, short]), vec![0xc3, 0xf0, 0x0f]);
+        assert_eq!(bit_op(BitOp::Not, &[short]), vec![0xc3]);
+        assert_eq!(bit_op(BitOp::Not, &[long]), vec![0x00, 0x0f, 0xf0]);
+        // A missing key reads as empty, which ANDs everything away
+        assert_eq!(bit_op(BitOp::And, &[long, &[]]), vec![0x00, 0x00, 0x00]);
+        assert_eq!(bit_op(BitOp::Not, &[&[]]), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let bytes = vec![0x00, 0x80, 0xff, 0x0a];
+        assert_eq!(encode(&bytes), "BITS0080ff0a");
+        assert_eq!(decode(&encode(&bytes)), Some(bytes));
+    }
+
+    #[test]
+    fn decode_rejects_malformed_payloads() {
+        assert_eq!(decode("0080"), None);
+        assert_eq!(decode("BITS"), None);
+        assert_eq!(decode("BITS008"), None);
+        assert_eq!(decode("BITSzz"), None);
+        assert_eq!(decode("BITS+1"), None);
+        assert_eq!(decode("BITSé0"), None);
+    }
+}
@@ -0,0 +1,83 @@
+// Time budget for commands that walk many keys under the data lock, from
+// --command-timeout-ms: BIGKEYS, SCANVALUE, METRICS and AGGREGATE. They
+// check the clock every CHECK_EVERY keys and give up with EXCEEDED once
+// over budget, which releases the lock. LCS compares its values after
+// releasing the lock and is bounded by its size cap instead.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+pub const EXCEEDED: &str = "ERROR: command exceeded time budget";
+
+// Keys between clock checks, so the check costs little per key
+const CHECK_EVERY: u32 = 256;
+
+// 0 leaves commands unbounded
+static TIMEOUT_MICROS: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_timeout(timeout: Duration) {
+    TIMEOUT_MICROS.store(timeout.as_micros() as u64, Ordering::Relaxed);
+}
+
+pub struct Budget {
+    deadline: Option<Instant>,
+    until_check: u32,
+}
+
+impl Budget {
+    // Start timing a command; waiting for the lock counts against it
+    pub fn start() -> Budget {
+        let micros = TIMEOUT_MICROS.load(Ordering::Relaxed);
+        Budget {
+            deadline: (micros > 0).then(|| Instant::now() + Duration::from_micros(micros)),
+            until_check: CHECK_EVERY,
+        }
+    }
+
+    // Call once per key examined; Err once the deadline has passed
+    pub fn check(&mut self) -> Result<(), String> {
+        let Some(deadline) = self.deadline else {
+            return Ok(());
+        };
+        self.until_check -= 1;
+        if self.until_check > 0 {
+            return Ok(());
+        }
+        self.until_check = CHECK_EVERY;
+        if Instant::now() >= deadline {
+            return Err(EXCEEDED.to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unbounded_budget_never_expires() {
+        let mut budget = Budget { deadline: None, until_check: CHECK_EVERY };
+        for _ in 0..CHECK_EVERY * 4 {
+            assert!(budget.check().is_ok());
+        }
+    }
+
+    #[test]
+    fn expired_budget_fails_at_the_next_clock_check() {
+        let mut budget = Budget { deadline: Some(Instant::now()), until_check: CHECK_EVERY };
+        for _ in 1..CHECK_EVERY {
+            assert!(budget.check().is_ok());
+        }
+        assert_eq!(budget.check(), Err(EXCEEDED.to_string()));
+    }
+
+    #[test]
+    fn budget_within_deadline_passes_clock_checks() {
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let mut budget = Budget { deadline: Some(deadline), until_check: CHECK_EVERY };
+        for _ in 0..CHECK_EVERY * 4 {
+            assert!(budget.check().is_ok());
+        }
+    }
+}
@@ -0,0 +1,156 @@
+// Value encodings for SET ... ENCODE and GET ... DECODE. Only the reply
+// and request boundary changes; the store and the WAL hold the encoded
+// form. Base64 is the standard alphabet with '=' padding.
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    None,
+    Base64,
+}
+
+impl Codec {
+    // Parse an ENCODE/DECODE argument, case-insensitively
+    pub fn parse(name: &str) -> Option<Codec> {
+        match name.to_ascii_lowercase().as_str() {
+            "none" => Some(Codec::None),
+            "base64" => Some(Codec::Base64),
+            _ => None,
+        }
+    }
+
+    pub fn encode(self, value: &str) -> String {
+        match self {
+            Codec::None => value.to_string(),
+            Codec::Base64 => base64_encode(value.as_bytes()),
+        }
+    }
+
+    // Decoded bytes, or None if value is not valid in this encoding
+    pub fn decode(self, value: &str) -> Option<Vec<u8>> {
+        match self {
+            Codec::None => Some(value.as_bytes().to_vec()),
+            Codec::Base64 => base64_decode(value),
+        }
+    }
+}
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// Strict decoding: length a multiple of 4, padding only at the end, and
+// no stray bits in the final character
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.as_bytes();
+    if !s.len().is_multiple_of(4) {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    for (i, chunk) in s.chunks(4).enumerate() {
+        let last = i == s.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+
+        let mut n = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let digit = ALPHABET.iter().position(|&a| a == c)?;
+            n = n << 6 | digit as u32;
+        }
+        n <<= 6 * padding;
+
+        let bytes = [(n >> 16) as u8, (n >> 8) as u8, n as u8];
+        let kept = 3 - padding;
+        if bytes[kept..].iter().any(|&b| b != 0) {
+            return None;
+        }
+        out.extend_from_slice(&bytes[..kept]);
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_pads_to_a_multiple_of_four() {
+        // No padding, one '=' and two '='
+        assert_eq!(base64_encode(b"abc"), "YWJj");
+        assert_eq!(base64_encode(b"ab"), "YWI=");
+        assert_eq!(base64_encode(b"a"), "YQ==");
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"hello world"), "aGVsbG8gd29ybGQ=");
+    }
+
+    #[test]
+    fn decode_handles_each_padding_length() {
+        assert_eq!(base64_decode("YWJj"), Some(b"abc".to_vec()));
+        assert_eq!(base64_decode("YWI="), Some(b"ab".to_vec()));
+        assert_eq!(base64_decode("YQ=="), Some(b"a".to_vec()));
+        assert_eq!(base64_decode(""), Some(Vec::new()));
+    }
+
+    #[test]
+    fn round_trip_covers_every_byte() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        for len in 0..bytes.len() {
+            assert_eq!(base64_decode(&base64_encode(&bytes[..len])), Some(bytes[..len].to_vec()));
+        }
+    }
+
+    #[test]
+    fn decode_rejects_invalid_characters() {
+        assert_eq!(base64_decode("YW*j"), None);
+        assert_eq!(base64_decode("YW-j"), None);
+        assert_eq!(base64_decode("YW j"), None);
+        assert_eq!(base64_decode("YWé="), None);
+    }
+
+    #[test]
+    fn decode_rejects_misplaced_padding() {
+        // Padding before the last chunk or inside a chunk
+        assert_eq!(base64_decode("YQ==YWJj"), None);
+        assert_eq!(base64_decode("Y=Jj"), None);
+        assert_eq!(base64_decode("=WJj"), None);
+        // Too much padding
+        assert_eq!(base64_decode("Y==="), None);
+        assert_eq!(base64_decode("===="), None);
+        // Length not a multiple of four, as with missing padding
+        assert_eq!(base64_decode("YQ"), None);
+        assert_eq!(base64_decode("YWI"), None);
+    }
+
+    #[test]
+    fn decode_rejects_stray_bits_before_padding() {
+        // "YR==" and "YWJ=" set bits that the padding says are unused
+        assert_eq!(base64_decode("YR=="), None);
+        assert_eq!(base64_decode("YWJ="), None);
+    }
+
+    #[test]
+    fn codec_parse_and_none() {
+        assert_eq!(Codec::parse("BASE64"), Some(Codec::Base64));
+        assert_eq!(Codec::parse("none"), Some(Codec::None));
+        assert_eq!(Codec::parse("hex"), None);
+        assert_eq!(Codec::None.encode("a b"), "a b");
+        assert_eq!(Codec::None.decode("a b"), Some(b"a b".to_vec()));
+    }
+}
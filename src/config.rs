@@ -0,0 +1,224 @@
+use std::fmt;
+
+// Seconds an IDEMPOTENT token is remembered without --idempotency-window
+pub const DEFAULT_IDEMPOTENCY_WINDOW_SECS: u64 = 300;
+
+// Default for --latency-threshold-ms
+pub const DEFAULT_LATENCY_THRESHOLD_MS: u64 = 100;
+
+// Most entries HISTORY returns without --history-max
+pub const DEFAULT_MAX_HISTORY_ENTRIES: usize = 100;
+
+// Server settings parsed from command-line flags
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    // Listen backlog passed to listen(); None keeps the std default
+    pub tcp_backlog: Option<i32>,
+    // Bind with SO_REUSEPORT so a successor can listen before this exits
+    pub reuse_port: bool,
+    pub wal_sync_mode: WalSyncMode,
+    // Estimated dataset size in bytes that startup replay refuses to exceed
+    pub max_memory: Option<u64>,
+    // Seconds to keep serving after a shutdown request while reporting
+    // not ready; None shuts down straight away
+    pub lame_duck_seconds: Option<u64>,
+    // host:port that committed writes are copied to, best-effort
+    pub forward_to: Option<String>,
+    // Store identical string values once, shared between keys
+    pub intern_values: bool,
+    // Keep large string values in a memory-mapped file instead of the heap
+    pub mmap_values: bool,
+    // Seconds an IDEMPOTENT token's reply is remembered; None uses the default
+    pub idempotency_window: Option<u64>,
+    // Seconds after which a connection is asked to reconnect; None never
+    pub max_connection_age: Option<u64>,
+    // Commands and WAL fsyncs at least this slow go in LATENCY HISTORY;
+    // None uses the default
+    pub latency_threshold_ms: Option<u64>,
+    // Milliseconds a command that walks many keys under the data lock may
+    // run before it gives up; None never
+    pub command_timeout_ms: Option<u64>,
+    // Most entries one HISTORY call returns; None uses the default
+    pub history_max: Option<usize>,
+    // (command, new name) pairs, uppercased; an empty new name disables
+    // the command
+    pub rename_commands: Vec<(String, String)>,
+}
+
+// How WAL appends are made durable; see README.md for the benchmark
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WalSyncMode {
+    // Explicit fsync after each append
+    #[default]
+    Fsync,
+    // Open the WAL with O_DSYNC so each write is durable on return
+    Dsync,
+}
+
+impl fmt::Display for WalSyncMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalSyncMode::Fsync => f.write_str("fsync"),
+            WalSyncMode::Dsync => f.write_str("dsync"),
+        }
+    }
+}
+
+impl std::str::FromStr for WalSyncMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fsync" => Ok(WalSyncMode::Fsync),
+            "dsync" => Ok(WalSyncMode::Dsync),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Config, String> {
+        let mut config = Config::default();
+
+        while let Some(flag) = args.next() {
+            match flag.as_str() {
+                "--tcp-backlog" => {
+                    let backlog = flag_value(&flag, args.next())?;
+                    config.tcp_backlog = Some(backlog);
+                }
+                "--reuse-port" => config.reuse_port = true,
+                "--wal-sync-mode" => {
+                    config.wal_sync_mode = flag_value(&flag, args.next())?;
+                }
+                "--maxmemory" => {
+                    let bytes = flag_value(&flag, args.next())?;
+                    config.max_memory = Some(bytes);
+                }
+                "--lame-duck-seconds" => {
+                    let seconds = flag_value(&flag, args.next())?;
+                    config.lame_duck_seconds = Some(seconds);
+                }
+                "--forward-to" => {
+                    config.forward_to = Some(flag_value(&flag, args.next())?);
+                }
+                "--intern-values" => config.intern_values = true,
+                "--mmap-values" => config.mmap_values = true,
+                "--idempotency-window" => {
+                    let seconds = flag_value(&flag, args.next())?;
+                    config.idempotency_window = Some(seconds);
+                }
+                "--rename-command" => {
+                    let command: String = flag_value(&flag, args.next())?;
+                    let new_name: String = flag_value(&flag, args.next())?;
+                    config.rename_commands.push((command.to_uppercase(), new_name.to_uppercase()));
+                }
+                "--latency-threshold-ms" => {
+                    let millis = flag_value(&flag, args.next())?;
+                    config.latency_threshold_ms = Some(millis);
+                }
+                "--command-timeout-ms" => {
+                    let millis = flag_value(&flag, args.next())?;
+                    config.command_timeout_ms = Some(millis);
+                }
+                "--history-max" => match flag_value(&flag, args.next())? {
+                    0 => return Err("ERROR: --history-max must be positive".to_string()),
+                    max => config.history_max = Some(max),
+                },
+                "--max-connection-age" => {
+                    let seconds = flag_value(&flag, args.next())?;
+                    config.max_connection_age = Some(seconds);
+                }
+                _ => return Err(format!("ERROR: Unknown flag '{}'", flag)),
+            }
+        }
+
+        Ok(config)
+    }
+
+    // Every setting as (flag name, effective value, set by a flag), for
+    // CONFIG DUMP. Unset optional settings show '-' for off.
+    pub fn dump(&self) -> Vec<(&'static str, String, bool)> {
+        let defaults = Config::default();
+        let or_off = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+        vec![
+            (
+                "tcp-backlog",
+                or_off(self.tcp_backlog.map(|n| n.to_string())),
+                self.tcp_backlog.is_some(),
+            ),
+            (
+                "reuse-port",
+                if self.reuse_port { "yes" } else { "no" }.to_string(),
+                self.reuse_port != defaults.reuse_port,
+            ),
+            (
+                "wal-sync-mode",
+                self.wal_sync_mode.to_string(),
+                self.wal_sync_mode != defaults.wal_sync_mode,
+            ),
+            (
+                "maxmemory",
+                or_off(self.max_memory.map(|n| n.to_string())),
+                self.max_memory.is_some(),
+            ),
+            (
+                "lame-duck-seconds",
+                or_off(self.lame_duck_seconds.map(|n| n.to_string())),
+                self.lame_duck_seconds.is_some(),
+            ),
+            ("forward-to", or_off(self.forward_to.clone()), self.forward_to.is_some()),
+            (
+                "intern-values",
+                if self.intern_values { "yes" } else { "no" }.to_string(),
+                self.intern_values != defaults.intern_values,
+            ),
+            (
+                "mmap-values",
+                if self.mmap_values { "yes" } else { "no" }.to_string(),
+                self.mmap_values != defaults.mmap_values,
+            ),
+            (
+                "idempotency-window",
+                self.idempotency_window.unwrap_or(DEFAULT_IDEMPOTENCY_WINDOW_SECS).to_string(),
+                self.idempotency_window.is_some(),
+            ),
+            (
+                "max-connection-age",
+                or_off(self.max_connection_age.map(|n| n.to_string())),
+                self.max_connection_age.is_some(),
+            ),
+            (
+                "latency-threshold-ms",
+                self.latency_threshold_ms.unwrap_or(DEFAULT_LATENCY_THRESHOLD_MS).to_string(),
+                self.latency_threshold_ms.is_some(),
+            ),
+            (
+                "history-max",
+                self.history_max.unwrap_or(DEFAULT_MAX_HISTORY_ENTRIES).to_string(),
+                self.history_max.is_some(),
+            ),
+            (
+                "command-timeout-ms",
+                or_off(self.command_timeout_ms.map(|n| n.to_string())),
+                self.command_timeout_ms.is_some(),
+            ),
+            // Only the affected commands; the new names stay secret
+            (
+                "rename-command",
+                or_off((!self.rename_commands.is_empty()).then(|| {
+                    let commands: Vec<&str> = self.rename_commands.iter().map(|(c, _)| c.as_str()).collect();
+                    commands.join(",")
+                })),
+                !self.rename_commands.is_empty(),
+            ),
+        ]
+    }
+}
+
+// Parse the value following a flag, naming the flag on failure
+fn flag_value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("ERROR: {} requires a value", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("ERROR: Invalid value '{}' for {}", value, flag))
+}
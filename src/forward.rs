@@ -0,0 +1,91 @@
+// Best-effort tee of committed WAL records to a TCP endpoint for
+// --forward-to. Records are queued without blocking the write path and
+// sent, as the same JSON lines the WAL holds, by a background thread that
+// reconnects as needed. Anything that can't be queued or sent, including
+// batches that arrive while waiting to reconnect, is dropped with a
+// warning and counted for INFO and METRICS.
+
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Batches of records waiting to be sent before new ones are dropped
+const QUEUE_CAPACITY: usize = 10_000;
+
+// Wait after a failed connect or send before trying the endpoint again
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// Warn on the first drop and then once per this many
+const WARN_EVERY_DROPS: u64 = 1000;
+
+pub struct Forwarder {
+    sender: SyncSender<Vec<u8>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Forwarder {
+    pub fn start(addr: String) -> Forwarder {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let run_dropped = Arc::clone(&dropped);
+        thread::spawn(move || run(addr, receiver, &run_dropped));
+        Forwarder { sender, dropped }
+    }
+
+    // Queue newline-terminated records; never blocks
+    pub fn forward(&self, records: Vec<u8>) {
+        if self.sender.try_send(records).is_err() {
+            note_dropped(&self.dropped, "forward queue full");
+        }
+    }
+
+    // Batches dropped so far, for any reason
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+// Count a dropped batch, warning on the first and then once per
+// WARN_EVERY_DROPS
+fn note_dropped(dropped: &AtomicU64, reason: &str) {
+    let dropped = dropped.fetch_add(1, Ordering::Relaxed) + 1;
+    if dropped == 1 || dropped.is_multiple_of(WARN_EVERY_DROPS) {
+        eprintln!("Warning: {}, {} forward batches dropped so far", reason, dropped);
+    }
+}
+
+fn run(addr: String, receiver: Receiver<Vec<u8>>, dropped: &AtomicU64) {
+    let mut conn: Option<TcpStream> = None;
+    let mut retry_at = Instant::now();
+
+    for records in receiver {
+        if conn.is_none() {
+            if Instant::now() < retry_at {
+                note_dropped(dropped, "waiting to reconnect to the forward target");
+                continue;
+            }
+            match TcpStream::connect(&addr) {
+                Ok(stream) => conn = Some(stream),
+                Err(e) => {
+                    eprintln!("Warning: cannot forward writes to {}: {}", addr, e);
+                    note_dropped(dropped, "forward target unreachable");
+                    retry_at = Instant::now() + RETRY_DELAY;
+                    continue;
+                }
+            }
+        }
+
+        if let Some(stream) = conn.as_mut()
+            && let Err(e) = stream.write_all(&records)
+        {
+            eprintln!("Warning: forwarding writes to {} failed: {}", addr, e);
+            note_dropped(dropped, "forward send failed");
+            conn = None;
+            retry_at = Instant::now() + RETRY_DELAY;
+        }
+    }
+}
@@ -0,0 +1,151 @@
+// Glob-style pattern matching as in Redis: '*' matches any run of
+// characters, '?' any one character, '[...]' one character from a set or
+// range ('^' negates), and '\' makes the next character literal.
+
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    match_from(&pattern, &text)
+}
+
+fn match_from(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position after the last '*' and the text position it is retried from
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match_class(&pattern[p..], text[t]),
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(2),
+            Some(&c) => (c == text[t]).then_some(1),
+            None => None,
+        };
+        match (step, star) {
+            (Some(len), _) => {
+                p += len;
+                t += 1;
+            }
+            // Let the last '*' swallow one more character and retry
+            (None, Some((after_star, from))) => {
+                p = after_star;
+                t = from + 1;
+                star = Some((after_star, from + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Match c against the class at the start of pattern; Some(pattern
+// characters consumed) if it matches. An unclosed class runs to the end.
+fn match_class(pattern: &[char], c: char) -> Option<usize> {
+    let mut i = 1;
+    let negate = pattern.get(i) == Some(&'^');
+    if negate {
+        i += 1;
+    }
+
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != ']' {
+        if pattern[i] == '\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == c;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+            let (lo, hi) = (pattern[i].min(pattern[i + 2]), pattern[i].max(pattern[i + 2]));
+            matched |= (lo..=hi).contains(&c);
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+    let consumed = (i + 1).min(pattern.len());
+    (matched != negate).then_some(consumed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn star_matches_any_run() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("user:*", "user:"));
+        assert!(glob_match("user:*", "user:42"));
+        assert!(glob_match("*:42", "user:42"));
+        assert!(glob_match("a*b*c", "aXXbYYc"));
+        assert!(glob_match("a*b", "abbb"));
+        assert!(glob_match("**", "x"));
+        assert!(!glob_match("user:*", "users:42"));
+        assert!(!glob_match("a*b", "abc"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character() {
+        assert!(glob_match("h?llo", "hello"));
+        assert!(glob_match("h?llo", "hallo"));
+        assert!(glob_match("?", "é"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(!glob_match("h?llo", "heello"));
+        assert!(!glob_match("?", ""));
+    }
+
+    #[test]
+    fn class_matches_listed_characters() {
+        assert!(glob_match("h[ae]llo", "hello"));
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[ae]llo", "hillo"));
+        assert!(!glob_match("h[ae]llo", "hllo"));
+    }
+
+    #[test]
+    fn class_matches_ranges() {
+        assert!(glob_match("[a-z]", "m"));
+        assert!(glob_match("[a-z]", "a"));
+        assert!(glob_match("[a-z]", "z"));
+        assert!(!glob_match("[a-z]", "M"));
+        assert!(glob_match("[0-9a-f]x", "ex"));
+        // Reversed bounds are swapped
+        assert!(glob_match("[z-a]", "m"));
+        // A '-' before ']' is literal
+        assert!(glob_match("[a-]", "-"));
+    }
+
+    #[test]
+    fn negated_class() {
+        assert!(glob_match("[^x]", "y"));
+        assert!(!glob_match("[^x]", "x"));
+        assert!(glob_match("[^a-c]z", "dz"));
+        assert!(!glob_match("[^a-c]z", "bz"));
+    }
+
+    #[test]
+    fn escaped_metacharacters_are_literal() {
+        assert!(glob_match("a\\*b", "a*b"));
+        assert!(!glob_match("a\\*b", "aXb"));
+        assert!(glob_match("a\\?", "a?"));
+        assert!(!glob_match("a\\?", "ab"));
+        assert!(glob_match("\\[x]", "[x]"));
+        assert!(glob_match("[\\]]", "]"));
+        assert!(glob_match("[\\^]", "^"));
+        // A trailing backslash matches itself
+        assert!(glob_match("a\\", "a\\"));
+    }
+
+    #[test]
+    fn unterminated_class_runs_to_the_end() {
+        assert!(glob_match("[abc", "a"));
+        assert!(glob_match("x[abc", "xc"));
+        assert!(!glob_match("[abc", "d"));
+        assert!(!glob_match("[abc", "ab"));
+        assert!(!glob_match("[", "a"));
+    }
+}
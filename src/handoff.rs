@@ -0,0 +1,101 @@
+// WAL ownership for --reuse-port handoffs. Two processes may share the
+// port, but only one may append to kvstore.log: each numbers its writes
+// from its own OP_SEQ, and startup compaction replaces the file. The
+// owner holds an exclusive flock on LOCK_PATH. A successor waits for it
+// before replaying and compacting; the old process gives it up when it
+// enters lame-duck mode and refuses writes from then on.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// A separate file, since compaction renames a new kvstore.log into place
+const LOCK_PATH: &str = "kvstore.lock";
+
+// The locked file while this process owns the WAL
+static OWNER: Mutex<Option<File>> = Mutex::new(None);
+
+// Set once acquire() succeeds; without it every write is allowed
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Take ownership of the WAL, waiting for a previous owner to give it up
+pub fn acquire() -> io::Result<()> {
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(LOCK_PATH)?;
+    if !try_lock(&file, libc::LOCK_EX | libc::LOCK_NB)? {
+        println!("Waiting for the previous server to hand over kvstore.log...");
+        try_lock(&file, libc::LOCK_EX)?;
+    }
+    *OWNER.lock().unwrap() = Some(file);
+    ENABLED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+// False if the lock is held elsewhere and LOCK_NB was given
+fn try_lock(file: &File, operation: libc::c_int) -> io::Result<bool> {
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.kind() == io::ErrorKind::WouldBlock {
+        return Ok(false);
+    }
+    Err(err)
+}
+
+// Give up the WAL once any write in progress has finished
+pub fn release() {
+    if ENABLED.load(Ordering::SeqCst) {
+        // Closing the file drops the flock
+        OWNER.lock().unwrap().take();
+    }
+}
+
+// True once this process has handed the WAL over
+pub fn released() -> bool {
+    ENABLED.load(Ordering::SeqCst) && OWNER.lock().unwrap().is_none()
+}
+
+// The error a write gets once the WAL belongs to a successor
+#[derive(Debug)]
+struct HandedOver;
+
+impl fmt::Display for HandedOver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("kvstore.log has been handed over to a successor")
+    }
+}
+
+impl std::error::Error for HandedOver {}
+
+// True if a write failed because the WAL was handed over meanwhile
+pub fn is_handed_over(error: &io::Error) -> bool {
+    error.get_ref().is_some_and(|inner| inner.is::<HandedOver>())
+}
+
+// Run a WAL write, failing instead if the WAL has been handed over. The
+// write holds ownership throughout, so release() waits for it.
+pub fn while_owner<T>(write: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return write();
+    }
+    let owner = OWNER.lock().unwrap();
+    if owner.is_none() {
+        return Err(io::Error::other(HandedOver));
+    }
+    write()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handed_over_errors_are_recognised() {
+        assert!(is_handed_over(&io::Error::other(HandedOver)));
+        assert!(!is_handed_over(&io::Error::other("disk full")));
+        assert!(!is_handed_over(&io::Error::from(io::ErrorKind::NotFound)));
+    }
+}
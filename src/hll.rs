@@ -0,0 +1,182 @@
+// HyperLogLog cardinality estimator with 2^12 one-byte registers
+// (~1.6% standard error). Serialized like Redis as a string starting
+// with "HYLL", followed here by the registers in hex.
+
+const PRECISION: u32 = 12;
+pub const REGISTERS: usize = 1 << PRECISION;
+const HEADER: &str = "HYLL";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hll {
+    registers: Vec<u8>,
+}
+
+impl Hll {
+    pub fn new() -> Hll {
+        Hll {
+            registers: vec![0; REGISTERS],
+        }
+    }
+
+    // Add an element; returns true if any register changed
+    pub fn add(&mut self, element: &[u8]) -> bool {
+        let hash = hash64(element);
+        let index = (hash & (REGISTERS as u64 - 1)) as usize;
+        // Rank of the first set bit in the remaining 64 - PRECISION bits
+        let rest = hash >> PRECISION;
+        let rank = (rest.trailing_zeros().min(64 - PRECISION) + 1) as u8;
+
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Fold another sketch into this one by taking per-register maxima
+    pub fn merge(&mut self, other: &Hll) {
+        for (mine, theirs) in self.registers.iter_mut().zip(&other.registers) {
+            *mine = (*mine).max(*theirs);
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+
+        // Small-range correction: linear counting while registers are empty
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let mut out = String::with_capacity(HEADER.len() + REGISTERS * 2);
+        out.push_str(HEADER);
+        for r in &self.registers {
+            out.push_str(&format!("{:02x}", r));
+        }
+        out
+    }
+
+    // Parse an encoded sketch; None if the string is not one
+    pub fn decode(s: &str) -> Option<Hll> {
+        let hex = s.strip_prefix(HEADER)?;
+        // from_str_radix alone would also accept a '+' sign
+        if hex.len() != REGISTERS * 2 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        let registers = (0..REGISTERS)
+            .map(|i| u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        Some(Hll { registers })
+    }
+}
+
+// FNV-1a followed by the murmur3 finalizer so every output bit depends on
+// every input bit. Fixed and seedless, so sketches replay identically.
+fn hash64(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^= h >> 33;
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Three standard errors, so a correct sketch essentially never fails
+    const TOLERANCE: f64 = 0.05;
+
+    fn sketch(range: std::ops::Range<u32>) -> Hll {
+        let mut hll = Hll::new();
+        for i in range {
+            hll.add(format!("element:{}", i).as_bytes());
+        }
+        hll
+    }
+
+    fn assert_close(estimate: u64, actual: u64) {
+        let error = (estimate as f64 - actual as f64).abs() / actual as f64;
+        assert!(error <= TOLERANCE, "estimate {} for {} is off by {:.3}", estimate, actual, error);
+    }
+
+    #[test]
+    fn empty_sketch_counts_zero() {
+        assert_eq!(Hll::new().count(), 0);
+    }
+
+    #[test]
+    fn count_is_within_error_bounds() {
+        for n in [100, 1_000, 10_000, 100_000] {
+            assert_close(sketch(0..n).count(), n as u64);
+        }
+    }
+
+    #[test]
+    fn repeated_elements_do_not_change_the_sketch() {
+        let mut hll = sketch(0..1_000);
+        assert!(!hll.add(b"element:0"));
+        assert_eq!(hll, sketch(0..1_000));
+    }
+
+    #[test]
+    fn merge_of_disjoint_sets_counts_the_union() {
+        let mut merged = sketch(0..5_000);
+        merged.merge(&sketch(5_000..10_000));
+        assert_close(merged.count(), 10_000);
+    }
+
+    #[test]
+    fn merge_of_overlapping_sets_counts_shared_elements_once() {
+        let mut merged = sketch(0..6_000);
+        merged.merge(&sketch(4_000..10_000));
+        assert_close(merged.count(), 10_000);
+        assert_eq!(merged, sketch(0..10_000));
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let hll = sketch(0..1_000);
+        let encoded = hll.encode();
+        assert!(encoded.starts_with(HEADER));
+        assert_eq!(encoded.len(), HEADER.len() + REGISTERS * 2);
+        assert_eq!(Hll::decode(&encoded), Some(hll));
+        assert_eq!(Hll::decode(&Hll::new().encode()), Some(Hll::new()));
+    }
+
+    #[test]
+    fn decode_rejects_malformed_payloads() {
+        let valid = sketch(0..100).encode();
+        let registers = &valid[HEADER.len()..];
+
+        // Wrong or missing header
+        assert_eq!(Hll::decode(registers), None);
+        assert_eq!(Hll::decode(&format!("HYLX{}", registers)), None);
+        // Too short and too long
+        assert_eq!(Hll::decode(&valid[..valid.len() - 2]), None);
+        assert_eq!(Hll::decode(&format!("{}00", valid)), None);
+        assert_eq!(Hll::decode(HEADER), None);
+        // Not hex, including a sign that from_str_radix would accept
+        assert_eq!(Hll::decode(&format!("{}zz", &valid[..valid.len() - 2])), None);
+        assert_eq!(Hll::decode(&format!("{}+1", &valid[..valid.len() - 2])), None);
+        // Multi-byte character where a hex digit should be
+        assert_eq!(Hll::decode(&format!("{}é", &valid[..valid.len() - 2])), None);
+    }
+}
@@ -0,0 +1,166 @@
+// Replies to recent writes sent with IDEMPOTENT <token>, so a client that
+// retries after losing a reply gets the original result instead of
+// applying the write twice. Tokens live in memory only, for the
+// --idempotency-window and at most CAPACITY at a time; a restart or an
+// evicted token makes the next retry a fresh write.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+// Tokens remembered at once; the oldest are forgotten first
+const CAPACITY: usize = 100_000;
+
+static CACHE: OnceLock<Mutex<Cache>> = OnceLock::new();
+
+struct Cache {
+    window: Duration,
+    records: HashMap<String, Record>,
+    // Tokens by first use, oldest first; may name tokens already removed
+    order: VecDeque<(Instant, String)>,
+}
+
+struct Record {
+    // Normalized command line the token was first used with
+    request: String,
+    // None while the first request is still running
+    reply: Option<Vec<u8>>,
+    seen: Instant,
+}
+
+// Outcome of presenting a token
+pub enum Claim {
+    // First use: run the command and complete the claim with its reply
+    New(Pending),
+    // Seen before: send this reply again
+    Replay(Vec<u8>),
+    // The first request with this token has not replied yet
+    Busy,
+    // The token was first used with a different command
+    Mismatch,
+}
+
+pub fn init(window: Duration) {
+    let _ = CACHE.set(Mutex::new(Cache {
+        window,
+        records: HashMap::new(),
+        order: VecDeque::new(),
+    }));
+}
+
+pub fn claim(token: &str, request: &str) -> Claim {
+    let mut cache = CACHE.get().expect("idempotency cache initialized").lock().unwrap();
+    let now = Instant::now();
+    cache.expire(now);
+
+    if let Some(record) = cache.records.get(token) {
+        return if record.request != request {
+            Claim::Mismatch
+        } else {
+            match &record.reply {
+                Some(reply) => Claim::Replay(reply.clone()),
+                None => Claim::Busy,
+            }
+        };
+    }
+
+    while cache.records.len() >= CAPACITY {
+        let Some((seen, oldest)) = cache.order.pop_front() else {
+            break;
+        };
+        cache.forget(&oldest, seen);
+    }
+    cache.records.insert(
+        token.to_string(),
+        Record { request: request.to_string(), reply: None, seen: now },
+    );
+    cache.order.push_back((now, token.to_string()));
+    Claim::New(Pending { token: token.to_string(), seen: now, completed: false })
+}
+
+pub fn len() -> usize {
+    CACHE.get().map_or(0, |cache| cache.lock().unwrap().records.len())
+}
+
+impl Cache {
+    fn expire(&mut self, now: Instant) {
+        while let Some((seen, _)) = self.order.front()
+            && now.duration_since(*seen) >= self.window
+        {
+            let (seen, token) = self.order.pop_front().unwrap();
+            self.forget(&token, seen);
+        }
+    }
+
+    // Remove token if its record is the one first seen at seen, not a
+    // later reuse after it was already forgotten once
+    fn forget(&mut self, token: &str, seen: Instant) {
+        if self.records.get(token).is_some_and(|record| record.seen == seen) {
+            self.records.remove(token);
+        }
+    }
+}
+
+// A claimed token awaiting its reply. Dropped without completing, as when
+// the connection fails mid-command, it frees the token for a retry.
+pub struct Pending {
+    token: String,
+    seen: Instant,
+    completed: bool,
+}
+
+impl Pending {
+    pub fn complete(mut self, reply: Vec<u8>) {
+        let mut cache = CACHE.get().unwrap().lock().unwrap();
+        if let Some(record) = cache.records.get_mut(&self.token)
+            && record.seen == self.seen
+        {
+            record.reply = Some(reply);
+        }
+        self.completed = true;
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if !self.completed {
+            CACHE.get().unwrap().lock().unwrap().forget(&self.token, self.seen);
+        }
+    }
+}
+
+// Writer that can also keep a copy of what passes through it, to save the
+// reply to an idempotent command
+pub struct Recorder<W> {
+    inner: W,
+    recording: Option<Vec<u8>>,
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(inner: W) -> Recorder<W> {
+        Recorder { inner, recording: None }
+    }
+
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Vec::new());
+    }
+
+    pub fn take_recording(&mut self) -> Vec<u8> {
+        self.recording.take().unwrap_or_default()
+    }
+}
+
+impl<W: Write> Write for Recorder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(recording) = &mut self.recording {
+            recording.extend_from_slice(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,80 @@
+// Shared storage for identical string values under --intern-values. Each
+// distinct string is kept once behind an Arc and every value holding it
+// shares that allocation. The table keeps one reference of its own and
+// drops the entry when the last value using it is dropped.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, OnceLock};
+
+// Present only when interning is enabled
+static TABLE: OnceLock<Mutex<HashSet<Arc<str>>>> = OnceLock::new();
+
+pub fn enable() {
+    let _ = TABLE.set(Mutex::new(HashSet::new()));
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SharedStr(Arc<str>);
+
+// Wrap a string value, reusing the interned copy if there is one
+pub fn share(value: String) -> SharedStr {
+    let Some(table) = TABLE.get() else {
+        return SharedStr(Arc::from(value));
+    };
+
+    let mut table = table.lock().unwrap();
+    if let Some(existing) = table.get(value.as_str()) {
+        return SharedStr(Arc::clone(existing));
+    }
+    let shared: Arc<str> = Arc::from(value);
+    table.insert(Arc::clone(&shared));
+    SharedStr(shared)
+}
+
+impl SharedStr {
+    // Values sharing this string, not counting the table's own reference.
+    // A string that is not interned belongs to one value alone; its Arc
+    // count would also include any clones a command is holding.
+    pub fn refcount(&self) -> usize {
+        if self.is_interned() { Arc::strong_count(&self.0) - 1 } else { 1 }
+    }
+
+    fn is_interned(&self) -> bool {
+        TABLE.get().is_some_and(|table| {
+            let table = table.lock().unwrap();
+            table.get(&*self.0).is_some_and(|entry| Arc::ptr_eq(entry, &self.0))
+        })
+    }
+}
+
+impl Drop for SharedStr {
+    fn drop(&mut self) {
+        // Only this value and the table still hold the string. Re-check
+        // under the table lock, since share() may have handed out another.
+        if let Some(table) = TABLE.get()
+            && Arc::strong_count(&self.0) == 2
+        {
+            let mut table = table.lock().unwrap();
+            let ours = table.get(&*self.0).is_some_and(|entry| Arc::ptr_eq(entry, &self.0));
+            if ours && Arc::strong_count(&self.0) == 2 {
+                table.remove(&*self.0);
+            }
+        }
+    }
+}
+
+impl Deref for SharedStr {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for SharedStr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
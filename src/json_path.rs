@@ -0,0 +1,179 @@
+// Minimal paths into JSON documents for JSONGET/JSONSET. Accepts dot
+// notation ("a.b.0", with "." or "$" for the root) or JSON Pointer
+// ("/a/b/0"). Numeric segments index arrays.
+
+use serde_json::Value as Json;
+
+pub fn parse_path(path: &str) -> Vec<String> {
+    if let Some(pointer) = path.strip_prefix('/') {
+        return pointer
+            .split('/')
+            .map(|segment| segment.replace("~1", "/").replace("~0", "~"))
+            .collect();
+    }
+
+    path.trim_start_matches('$')
+        .split('.')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| segment.to_string())
+        .collect()
+}
+
+// Array index for a segment: plain digits without a leading zero, as in
+// JSON Pointer, so "+1" and "01" name no element
+fn index(segment: &str) -> Option<usize> {
+    let digits = !segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit());
+    if !digits || (segment.len() > 1 && segment.starts_with('0')) {
+        return None;
+    }
+    segment.parse().ok()
+}
+
+pub fn get<'a>(doc: &'a Json, path: &[String]) -> Option<&'a Json> {
+    path.iter().try_fold(doc, |node, segment| match node {
+        Json::Object(map) => map.get(segment),
+        Json::Array(items) => items.get(index(segment)?),
+        _ => None,
+    })
+}
+
+// Replace the node at path. Object members are created if missing, but
+// every parent must already exist and array indexes must be in range.
+pub fn set(doc: &mut Json, path: &[String], new: Json) -> Result<(), String> {
+    let Some((last, parents)) = path.split_last() else {
+        *doc = new;
+        return Ok(());
+    };
+
+    let mut node = doc;
+    for segment in parents {
+        node = match node {
+            Json::Object(map) => map.get_mut(segment),
+            Json::Array(items) => index(segment).and_then(|i| items.get_mut(i)),
+            _ => None,
+        }
+        .ok_or_else(|| "ERROR: JSON path does not exist".to_string())?;
+    }
+
+    match node {
+        Json::Object(map) => {
+            map.insert(last.clone(), new);
+            Ok(())
+        }
+        Json::Array(items) => {
+            let slot = index(last)
+                .and_then(|i| items.get_mut(i))
+                .ok_or_else(|| "ERROR: JSON array index out of range".to_string())?;
+            *slot = new;
+            Ok(())
+        }
+        _ => Err("ERROR: JSON path does not exist".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn path(s: &str) -> Vec<String> {
+        parse_path(s)
+    }
+
+    fn doc() -> Json {
+        json!({"a": {"b": [10, {"c": "deep"}, [true, null]]}, "n": 1})
+    }
+
+    #[test]
+    fn parse_dot_and_pointer_paths() {
+        assert_eq!(path("a.b.0"), vec!["a", "b", "0"]);
+        assert_eq!(path("$.a.b"), vec!["a", "b"]);
+        assert_eq!(path("/a/b/0"), vec!["a", "b", "0"]);
+        assert_eq!(path("/a~1b/c~0d"), vec!["a/b", "c~d"]);
+        assert!(path(".").is_empty());
+        assert!(path("$").is_empty());
+        assert!(path("").is_empty());
+    }
+
+    #[test]
+    fn get_nested_object_and_array_paths() {
+        let doc = doc();
+        assert_eq!(get(&doc, &path("a.b.0")), Some(&json!(10)));
+        assert_eq!(get(&doc, &path("a.b.1.c")), Some(&json!("deep")));
+        assert_eq!(get(&doc, &path("/a/b/2/1")), Some(&Json::Null));
+        assert_eq!(get(&doc, &path("$")), Some(&doc));
+    }
+
+    #[test]
+    fn get_out_of_range_or_missing_is_none() {
+        let doc = doc();
+        assert_eq!(get(&doc, &path("a.b.3")), None);
+        assert_eq!(get(&doc, &path("a.b.2.2")), None);
+        assert_eq!(get(&doc, &path("a.x")), None);
+        // Through a scalar
+        assert_eq!(get(&doc, &path("n.x")), None);
+    }
+
+    #[test]
+    fn invalid_index_syntax_names_no_element() {
+        let doc = doc();
+        for bad in ["a.b.-1", "a.b.+1", "a.b.01", "a.b.x", "a.b.[0]", "a.b.1e0", "/a/b/"] {
+            assert_eq!(get(&doc, &path(bad)), None, "{}", bad);
+        }
+        // Bracket notation is not supported; it reads as a member name
+        assert_eq!(get(&doc, &path("a[b]")), None);
+    }
+
+    #[test]
+    fn set_replaces_nested_nodes() {
+        let mut doc = doc();
+        set(&mut doc, &path("a.b.1.c"), json!("new")).unwrap();
+        set(&mut doc, &path("/a/b/0"), json!(11)).unwrap();
+        assert_eq!(get(&doc, &path("a.b.1.c")), Some(&json!("new")));
+        assert_eq!(get(&doc, &path("a.b.0")), Some(&json!(11)));
+
+        set(&mut doc, &path("$"), json!([1])).unwrap();
+        assert_eq!(doc, json!([1]));
+    }
+
+    #[test]
+    fn set_creates_a_missing_member_of_an_existing_object() {
+        let mut doc = doc();
+        set(&mut doc, &path("a.added"), json!({"x": 1})).unwrap();
+        assert_eq!(get(&doc, &path("a.added.x")), Some(&json!(1)));
+    }
+
+    #[test]
+    fn set_with_a_missing_parent_fails() {
+        let mut doc = doc();
+        let before = doc.clone();
+        assert_eq!(
+            set(&mut doc, &path("a.missing.x"), json!(1)),
+            Err("ERROR: JSON path does not exist".to_string())
+        );
+        assert_eq!(
+            set(&mut doc, &path("n.x"), json!(1)),
+            Err("ERROR: JSON path does not exist".to_string())
+        );
+        assert_eq!(doc, before);
+    }
+
+    #[test]
+    fn set_out_of_range_or_invalid_index_fails() {
+        let mut doc = doc();
+        let before = doc.clone();
+        for bad in ["a.b.3", "a.b.-1", "a.b.+1", "a.b.01", "a.b.x"] {
+            assert_eq!(
+                set(&mut doc, &path(bad), json!(1)),
+                Err("ERROR: JSON array index out of range".to_string()),
+                "{}",
+                bad
+            );
+        }
+        assert_eq!(
+            set(&mut doc, &path("a.b.9.c"), json!(1)),
+            Err("ERROR: JSON path does not exist".to_string())
+        );
+        assert_eq!(doc, before);
+    }
+}
@@ -0,0 +1,114 @@
+// Latency spikes for LATENCY HISTORY: slow command executions and WAL
+// fsyncs at or above the --latency-threshold-ms, with when they ended and
+// what caused them. Only the most recent MAX_EVENTS are kept. Every WAL
+// fsync is also timed for the percentiles METRICS reports.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::DEFAULT_LATENCY_THRESHOLD_MS;
+
+const MAX_EVENTS: usize = 128;
+
+static THRESHOLD_MICROS: AtomicU64 = AtomicU64::new(DEFAULT_LATENCY_THRESHOLD_MS * 1000);
+
+static EVENTS: Mutex<VecDeque<Event>> = Mutex::new(VecDeque::new());
+
+// Most recent WAL fsync times kept for percentiles
+const MAX_FSYNC_SAMPLES: usize = 1024;
+
+static FSYNC_MICROS: Mutex<VecDeque<u64>> = Mutex::new(VecDeque::new());
+
+#[derive(Clone)]
+pub struct Event {
+    // Wall-clock millis when the slow operation finished
+    pub ts: u64,
+    pub cause: String,
+    pub micros: u64,
+}
+
+pub fn set_threshold(threshold: Duration) {
+    THRESHOLD_MICROS.store(threshold.as_micros() as u64, Ordering::Relaxed);
+}
+
+// Keep the event if it took at least the threshold; cause is only built
+// for events that are kept
+pub fn record(elapsed: Duration, ts: u64, cause: impl FnOnce() -> String) {
+    let micros = elapsed.as_micros() as u64;
+    if micros < THRESHOLD_MICROS.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut events = EVENTS.lock().unwrap();
+    if events.len() == MAX_EVENTS {
+        events.pop_front();
+    }
+    events.push_back(Event { ts, cause: cause(), micros });
+}
+
+// WAL fsync times in microseconds over the kept samples; all 0 before
+// the first fsync
+#[derive(Debug, Default, PartialEq)]
+pub struct FsyncSummary {
+    pub samples: usize,
+    pub p50: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+// Time one WAL fsync, which is also an event if slow
+pub fn record_fsync(elapsed: Duration, ts: u64) {
+    let mut samples = FSYNC_MICROS.lock().unwrap();
+    if samples.len() == MAX_FSYNC_SAMPLES {
+        samples.pop_front();
+    }
+    samples.push_back(elapsed.as_micros() as u64);
+    drop(samples);
+
+    record(elapsed, ts, || "wal-sync".to_string());
+}
+
+pub fn fsync_summary() -> FsyncSummary {
+    summarize(FSYNC_MICROS.lock().unwrap().iter().copied().collect())
+}
+
+fn summarize(mut samples: Vec<u64>) -> FsyncSummary {
+    if samples.is_empty() {
+        return FsyncSummary::default();
+    }
+    samples.sort_unstable();
+    let at = |percent: usize| samples[(samples.len() * percent / 100).min(samples.len() - 1)];
+    FsyncSummary {
+        samples: samples.len(),
+        p50: at(50),
+        p99: at(99),
+        max: samples[samples.len() - 1],
+    }
+}
+
+// Kept events, newest first
+pub fn history() -> Vec<Event> {
+    EVENTS.lock().unwrap().iter().rev().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_of_no_samples_is_zero() {
+        assert_eq!(summarize(Vec::new()), FsyncSummary::default());
+    }
+
+    #[test]
+    fn summary_picks_percentiles_from_sorted_samples() {
+        let samples: Vec<u64> = (1..=100).rev().collect();
+        let summary = summarize(samples);
+        assert_eq!(summary, FsyncSummary { samples: 100, p50: 51, p99: 100, max: 100 });
+
+        let summary = summarize(vec![7]);
+        assert_eq!(summary, FsyncSummary { samples: 1, p50: 7, p99: 7, max: 7 });
+    }
+}
@@ -0,0 +1,107 @@
+// Longest common subsequence of two strings for LCS, by the classic
+// dynamic program over characters. Both time and the table for
+// reconstructing the string are O(n*m), so callers cap the input sizes.
+
+// Length only, keeping just two rows of the table
+pub fn lcs_len(a: &[char], b: &[char]) -> usize {
+    let mut prev = vec![0usize; b.len() + 1];
+    let mut curr = vec![0usize; b.len() + 1];
+    for ca in a {
+        for (j, cb) in b.iter().enumerate() {
+            curr[j + 1] = if ca == cb { prev[j] + 1 } else { prev[j + 1].max(curr[j]) };
+        }
+        std::mem::swap(&mut prev, &mut curr);
+    }
+    prev[b.len()]
+}
+
+pub fn lcs(a: &[char], b: &[char]) -> String {
+    let width = b.len() + 1;
+    let mut table = vec![0u32; (a.len() + 1) * width];
+    for (i, ca) in a.iter().enumerate() {
+        for (j, cb) in b.iter().enumerate() {
+            table[(i + 1) * width + j + 1] = if ca == cb {
+                table[i * width + j] + 1
+            } else {
+                table[i * width + j + 1].max(table[(i + 1) * width + j])
+            };
+        }
+    }
+
+    // Walk back from the bottom-right corner, collecting matches
+    let (mut i, mut j) = (a.len(), b.len());
+    let mut out = Vec::with_capacity(table[i * width + j] as usize);
+    while i > 0 && j > 0 {
+        if a[i - 1] == b[j - 1] {
+            out.push(a[i - 1]);
+            i -= 1;
+            j -= 1;
+        } else if table[(i - 1) * width + j] >= table[i * width + j - 1] {
+            i -= 1;
+        } else {
+            j -= 1;
+        }
+    }
+    out.iter().rev().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+
+    // LCS string and LEN for a pair, checking they agree
+    fn both(a: &str, b: &str) -> (String, usize) {
+        let (a, b) = (chars(a), chars(b));
+        let string = lcs(&a, &b);
+        let len = lcs_len(&a, &b);
+        assert_eq!(string.chars().count(), len);
+        (string, len)
+    }
+
+    // True if sub can be obtained from s by deleting characters
+    fn is_subsequence(sub: &str, s: &str) -> bool {
+        let mut rest = s.chars();
+        sub.chars().all(|c| rest.any(|r| r == c))
+    }
+
+    #[test]
+    fn empty_inputs() {
+        assert_eq!(both("", ""), (String::new(), 0));
+        assert_eq!(both("abc", ""), (String::new(), 0));
+        assert_eq!(both("", "abc"), (String::new(), 0));
+    }
+
+    #[test]
+    fn identical_strings() {
+        assert_eq!(both("ohmytext", "ohmytext"), ("ohmytext".to_string(), 8));
+        assert_eq!(both("a", "a"), ("a".to_string(), 1));
+    }
+
+    #[test]
+    fn no_common_characters() {
+        assert_eq!(both("abc", "xyz"), (String::new(), 0));
+    }
+
+    #[test]
+    fn known_answers() {
+        // The example from the Redis LCS documentation
+        assert_eq!(both("ohmytext", "mynewtext"), ("mytext".to_string(), 6));
+        assert_eq!(both("AGGTAB", "GXTXAYB"), ("GTAB".to_string(), 4));
+        assert_eq!(both("abcdef", "acf"), ("acf".to_string(), 3));
+        // Multi-byte characters count as one each
+        assert_eq!(both("héllo", "hallö"), ("hll".to_string(), 3));
+    }
+
+    #[test]
+    fn tied_answers_are_a_longest_common_subsequence() {
+        // Several subsequences of length 4 exist; any of them is correct
+        let (a, b) = ("ABCBDAB", "BDCABA");
+        let (string, len) = both(a, b);
+        assert_eq!(len, 4);
+        assert!(is_subsequence(&string, a) && is_subsequence(&string, b), "{}", string);
+    }
+}
@@ -0,0 +1,93 @@
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::io::FromRawFd;
+
+// Backlog std's TcpListener::bind uses, for when only SO_REUSEPORT is wanted
+pub const DEFAULT_BACKLOG: i32 = 128;
+
+// Bind a listener with an explicit listen() backlog and, optionally,
+// SO_REUSEPORT so another process can bind the same port alongside it.
+// std's TcpListener::bind fixes both, so the socket is built by hand and
+// then wrapped.
+pub fn bind(addr: SocketAddr, backlog: i32, reuse_port: bool) -> io::Result<TcpListener> {
+    let domain = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+
+    unsafe {
+        let fd = libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Owning the fd first means it is closed on every error path below
+        let listener = TcpListener::from_raw_fd(fd);
+
+        // Match std, which sets SO_REUSEADDR so restarts can rebind quickly
+        let enable: libc::c_int = 1;
+        if libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEADDR,
+            &enable as *const _ as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        ) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        if reuse_port
+            && libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_REUSEPORT,
+                &enable as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            ) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        let (storage, len) = sockaddr_from(addr);
+        if libc::bind(fd, &storage as *const _ as *const libc::sockaddr, len) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if libc::listen(fd, backlog) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(listener)
+    }
+}
+
+// Convert a std address into the C representation bind() expects
+fn sockaddr_from(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+
+    let len = match addr {
+        SocketAddr::V4(v4) => {
+            let sin = &mut storage as *mut _ as *mut libc::sockaddr_in;
+            unsafe {
+                (*sin).sin_family = libc::AF_INET as libc::sa_family_t;
+                (*sin).sin_port = v4.port().to_be();
+                (*sin).sin_addr.s_addr = u32::from(*v4.ip()).to_be();
+            }
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(v6) => {
+            let sin6 = &mut storage as *mut _ as *mut libc::sockaddr_in6;
+            unsafe {
+                (*sin6).sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                (*sin6).sin6_port = v6.port().to_be();
+                (*sin6).sin6_addr.s6_addr = v6.ip().octets();
+                (*sin6).sin6_flowinfo = v6.flowinfo();
+                (*sin6).sin6_scope_id = v6.scope_id();
+            }
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, len as libc::socklen_t)
+}
@@ -0,0 +1,90 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
+
+// Time one in this many acquisitions of the data lock
+const SAMPLE_EVERY: u64 = 16;
+
+// Most recent wait samples kept for avg/p99
+const MAX_SAMPLES: usize = 1024;
+
+// Re-check the contention warning after this many new samples
+const CHECK_EVERY_SAMPLES: u64 = 256;
+
+// Warn when the p99 wait across the kept samples exceeds this
+const WARN_P99_MICROS: u64 = 10_000;
+
+// Sampled wait times for the central data mutex
+pub struct LockStats {
+    acquisitions: AtomicU64,
+    samples_taken: AtomicU64,
+    samples: Mutex<VecDeque<u64>>,
+}
+
+pub static LOCK_STATS: LockStats = LockStats {
+    acquisitions: AtomicU64::new(0),
+    samples_taken: AtomicU64::new(0),
+    samples: Mutex::new(VecDeque::new()),
+};
+
+impl LockStats {
+    // Acquire the mutex, timing the wait on a sampled fraction of calls
+    pub fn lock<'a, T>(&self, mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+        let n = self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        if !n.is_multiple_of(SAMPLE_EVERY) {
+            return mutex.lock().unwrap();
+        }
+
+        let start = Instant::now();
+        let guard = mutex.lock().unwrap();
+        self.record(start.elapsed().as_micros() as u64);
+        guard
+    }
+
+    fn record(&self, wait_micros: u64) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == MAX_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(wait_micros);
+
+        let taken = self.samples_taken.fetch_add(1, Ordering::Relaxed) + 1;
+        if taken.is_multiple_of(CHECK_EVERY_SAMPLES) {
+            let (avg, p99) = summarize(&samples);
+            if p99 > WARN_P99_MICROS {
+                eprintln!(
+                    "Warning: lock contention, p99 wait {}us (avg {}us) over last {} samples",
+                    p99,
+                    avg,
+                    samples.len()
+                );
+            }
+        }
+    }
+
+    pub fn acquisitions(&self) -> u64 {
+        self.acquisitions.load(Ordering::Relaxed)
+    }
+
+    pub fn samples_taken(&self) -> u64 {
+        self.samples_taken.load(Ordering::Relaxed)
+    }
+
+    // Average and p99 wait in microseconds over the kept samples
+    pub fn summary(&self) -> (u64, u64) {
+        summarize(&self.samples.lock().unwrap())
+    }
+}
+
+fn summarize(samples: &VecDeque<u64>) -> (u64, u64) {
+    if samples.is_empty() {
+        return (0, 0);
+    }
+
+    let mut sorted: Vec<u64> = samples.iter().copied().collect();
+    sorted.sort_unstable();
+    let avg = sorted.iter().sum::<u64>() / sorted.len() as u64;
+    let p99 = sorted[(sorted.len() * 99 / 100).min(sorted.len() - 1)];
+    (avg, p99)
+}
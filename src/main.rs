@@ -1,26 +1,590 @@
-use std::io::{self, BufRead, BufReader, Write};
+mod bitmap;
+mod budget;
+mod codec;
+mod config;
+mod forward;
+mod glob;
+mod handoff;
+mod hll;
+mod idempotency;
+mod intern;
+mod json_path;
+mod latency;
+mod lcs;
+mod listener;
+mod lock_stats;
+mod mmap_values;
+mod stream;
+mod tracking;
+
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
 use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
 use serde::{Serialize, Deserialize};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::fmt;
+use config::{
+    Config, WalSyncMode, DEFAULT_IDEMPOTENCY_WINDOW_SECS, DEFAULT_LATENCY_THRESHOLD_MS, DEFAULT_MAX_HISTORY_ENTRIES,
+};
+use forward::Forwarder;
+use hll::Hll;
+use idempotency::{Claim, Recorder};
+use intern::SharedStr;
+use bitmap::BitOp;
+use codec::Codec;
+use lock_stats::LOCK_STATS;
+use mmap_values::MappedStr;
+use stream::{Stream, StreamEntry, StreamId};
+use tracking::Tracker;
 
 
+// Variant names double as the WAL record tags, so they stay uppercase
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Serialize, Deserialize)]
 enum Command {
-    SET {key: String, value: String},
-    GET {key: String},
-    DELETE {key: String}
+    SET {
+        key: String,
+        value: String,
+        // Only set when value is the encoded form of a typed value
+        #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+        kind: Option<ValueType>,
+        // SET ... GET option; a reply detail, never written to the WAL
+        #[serde(skip)]
+        get: bool,
+    },
+    // timeout is None to wait indefinitely
+    BGET {key: String, timeout: Option<Duration>},
+    GET {
+        key: String,
+        // GET ... DECODE option; Codec::None replies with the stored form
+        #[serde(skip)]
+        decode: Codec,
+    },
+    GETRANGE {key: String, start: i64, end: i64},
+    SETRANGE {key: String, offset: usize, value: String},
+    DELETE {key: String},
+    UNSET {key: String},
+    INCR {key: String},
+    DECR {key: String},
+    DECRFLOOR {key: String, amount: i64},
+    CLAMPINCR {key: String, delta: i64, min: i64, max: i64},
+    RATELIMIT {key: String, limit: u64, window: u64},
+    OBJECT {subcommand: ObjectSubcommand, key: String},
+    HISTORY {key: String, count: usize},
+    BIGKEYS {count: usize},
+    DRYRUN {enabled: bool},
+    OPSEQ {enabled: bool},
+    VERBOSE {enabled: bool},
+    DURABILITY {relaxed: bool},
+    // 0 removes the limit
+    MAXREPLY {bytes: usize},
+    GETVER {key: String},
+    SETVER {key: String, value: String, expected: u64},
+    SWAP {key: String, value: String},
+    SWAPKEYS {key1: String, key2: String},
+    PFADD {key: String, elements: Vec<String>},
+    PFCOUNT {keys: Vec<String>},
+    PFMERGE {dest: String, sources: Vec<String>},
+    SETBIT {key: String, offset: u64, bit: bool},
+    GETBIT {key: String, offset: u64},
+    BITCOUNT {key: String, range: Option<(i64, i64)>},
+    BITOP {op: BitOp, dest: String, sources: Vec<String>},
+    JSONSET {key: String, path: String, value: String},
+    JSONGET {key: String, path: String},
+    CMPEQ {key1: String, key2: String},
+    AGGREGATE {op: AggregateOp, keys: Vec<String>},
+    SCANVALUE {cursor: Option<String>, pattern: String},
+    LCS {key1: String, key2: String, len_only: bool},
+    CYCLE {key: String, ring: Vec<String>},
+    INITIF {key: String, value: String},
+    GETORSET {key: String, default: String},
+    SETIF {cond_key: String, expected: String, pairs: Vec<(String, String)>},
+    CADEL {key: String, expected: String},
+    // id is None for '*'; the logged record always carries the assigned ID
+    XADD {key: String, id: Option<StreamId>, fields: Vec<(String, String)>},
+    XRANGE {key: String, start: StreamId, end: StreamId},
+    XLEN {key: String},
+    XREAD {key: String, after: StreamId, count: Option<usize>},
+    // id is None for '$'; the logged record always carries the resolved ID
+    XGROUP {key: String, group: String, id: Option<StreamId>},
+    // id is None for '>', which delivers new entries and is logged; any
+    // other ID re-reads the consumer's pending entries
+    XREADGROUP {group: String, consumer: String, key: String, id: Option<StreamId>, count: Option<usize>},
+    XACK {key: String, group: String, ids: Vec<StreamId>},
+    INFO,
+    METRICS,
+    CONFIG {subcommand: ConfigSubcommand},
+    CLIENT {subcommand: ClientSubcommand},
+    LATENCY {subcommand: LatencySubcommand},
+    TIME,
+    SELFTEST,
+    // SHUTDOWN LAMEDUCK; plain SHUTDOWN is not supported
+    SHUTDOWN,
+    BULKLOAD {phase: BulkPhase},
+    // Written by compaction only, to carry a key's version across restarts
+    SNAPSHOT {
+        key: String,
+        value: String,
+        #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+        kind: Option<ValueType>,
+        version: u64,
+    },
+    // Written by compaction only, to carry the write sequence across restarts
+    LASTSEQ {seq: u64}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum BulkPhase {
+    Begin,
+    End,
+}
+
+// SETs buffered by a connection between BULKLOAD BEGIN and END. They are
+// acknowledged before they are logged, so dropping a load flushes what
+// is left, even when the connection ends on an I/O error, and
+// UNLOGGED_BULK_SETS counts them until then.
+struct BulkLoad {
+    data: Arc<Mutex<HashMap<String, Entry>>>,
+    pending: Vec<Command>,
+    loaded: usize,
+}
+
+impl Drop for BulkLoad {
+    fn drop(&mut self) {
+        let data = Arc::clone(&self.data);
+        if let Err(e) = flush_bulk(&data, self) {
+            eprintln!("Error flushing bulk load: {}", e);
+            // Lost, but no longer holding up a WAL handoff
+            UNLOGGED_BULK_SETS.fetch_sub(self.pending.len() as u64, Ordering::SeqCst);
+        }
+        tracking::publish_written();
+    }
+}
+
+impl Command {
+    // Commands that mutate the store and append to the WAL
+    fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::SET { .. }
+                | Command::DELETE { .. }
+                | Command::UNSET { .. }
+                | Command::INCR { .. }
+                | Command::DECR { .. }
+                | Command::DECRFLOOR { .. }
+                | Command::CLAMPINCR { .. }
+                | Command::RATELIMIT { .. }
+                | Command::SETRANGE { .. }
+                | Command::SETVER { .. }
+                | Command::SWAP { .. }
+                | Command::SWAPKEYS { .. }
+                | Command::PFADD { .. }
+                | Command::PFMERGE { .. }
+                | Command::SETBIT { .. }
+                | Command::BITOP { .. }
+                | Command::JSONSET { .. }
+                | Command::CYCLE { .. }
+                | Command::INITIF { .. }
+                | Command::GETORSET { .. }
+                | Command::SETIF { .. }
+                | Command::CADEL { .. }
+                | Command::XADD { .. }
+                | Command::XGROUP { .. }
+                | Command::XREADGROUP { id: None, .. }
+                | Command::XACK { .. }
+        )
+    }
+
+    // Commands DRYRUN holds back: writes, plus SELFTEST, which logs a
+    // canary key, and SHUTDOWN LAMEDUCK
+    fn changes_state(&self) -> bool {
+        self.is_write() || matches!(self, Command::SELFTEST | Command::SHUTDOWN)
+    }
+
+    // Keys a read command returns data from, for CLIENT TRACKING
+    fn read_keys(&self) -> Vec<&str> {
+        match self {
+            Command::GET { key, .. }
+            | Command::BGET { key, .. }
+            | Command::GETRANGE { key, .. }
+            | Command::GETVER { key }
+            | Command::GETORSET { key, .. }
+            | Command::GETBIT { key, .. }
+            | Command::BITCOUNT { key, .. }
+            | Command::JSONGET { key, .. }
+            | Command::XRANGE { key, .. }
+            | Command::XLEN { key }
+            | Command::XREAD { key, .. } => vec![key],
+            Command::CMPEQ { key1, key2 } | Command::LCS { key1, key2, .. } => vec![key1, key2],
+            Command::PFCOUNT { keys } | Command::AGGREGATE { keys, .. } => {
+                keys.iter().map(String::as_str).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // Key a logged record writes to
+    fn written_key(&self) -> Option<&str> {
+        match self {
+            Command::SET { key, .. }
+            | Command::DELETE { key }
+            | Command::SNAPSHOT { key, .. }
+            | Command::PFADD { key, .. }
+            | Command::SETBIT { key, .. }
+            | Command::XADD { key, .. }
+            | Command::XGROUP { key, .. }
+            | Command::XREADGROUP { key, .. }
+            | Command::XACK { key, .. } => Some(key),
+            Command::PFMERGE { dest, .. } | Command::BITOP { dest, .. } => Some(dest),
+            _ => None,
+        }
+    }
+}
+
+// One WAL line: a command plus the wall-clock millis it was logged at and
+// its write sequence number. Compacted records and logs from older
+// versions carry neither.
+#[derive(Debug, Serialize, Deserialize)]
+struct LogRecord<C> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ts: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    #[serde(flatten)]
+    command: C,
+}
+
+// Type tag on SET and SNAPSHOT records whose value is not a plain string.
+// Only tagged values are decoded; an untagged one is always a string,
+// whatever it looks like.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ValueType {
+    #[serde(rename = "hyperloglog")]
+    Hll,
+    Bitmap,
+    Stream,
+}
+
+impl ValueType {
+    // Name as logged, which is also the name BIGKEYS reports
+    fn name(self) -> &'static str {
+        match self {
+            ValueType::Hll => "hyperloglog",
+            ValueType::Bitmap => "bitmap",
+            ValueType::Stream => "stream",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+enum AggregateOp {
+    Sum,
+    Min,
+    Max,
+    Avg,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum LatencySubcommand {
+    History,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum ClientSubcommand {
+    Tracking { enabled: bool },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum ConfigSubcommand {
+    Dump,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum ObjectSubcommand {
+    Encoding,
+    Refcount,
+}
+
+// In-memory value; canonical integer strings are stored natively so
+// counters skip re-parsing, and HyperLogLog, bitmap and stream values
+// are kept decoded. Other strings may be shared under --intern-values,
+// or kept in a mapped file under --mmap-values if large. The WAL still
+// records the string form, tagged with its ValueType if it has one.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Str(SharedStr),
+    Mapped(MappedStr),
+    Int(i64),
+    Hll(Hll),
+    Bitmap(Vec<u8>),
+    Stream(Stream),
+}
+
+impl Value {
+    // Value for a logged string and its type tag; a tagged value that no
+    // longer decodes is kept as a plain string
+    fn from_logged(value: String, kind: Option<ValueType>) -> Value {
+        let decoded = match kind {
+            Some(ValueType::Hll) => Hll::decode(&value).map(Value::Hll),
+            Some(ValueType::Bitmap) => bitmap::decode(&value).map(Value::Bitmap),
+            Some(ValueType::Stream) => Stream::decode(&value).map(Value::Stream),
+            None => None,
+        };
+        decoded.unwrap_or_else(|| Value::from_string(value))
+    }
+
+    fn from_string(value: String) -> Value {
+        if let Some(mapped) = mmap_values::store(&value) {
+            return Value::Mapped(mapped);
+        }
+        match value.parse::<i64>() {
+            Ok(n) if n.to_string() == value => Value::Int(n),
+            _ => Value::Str(intern::share(value)),
+        }
+    }
+
+    // Tag to log this value's string form with
+    fn kind(&self) -> Option<ValueType> {
+        match self {
+            Value::Str(_) | Value::Mapped(_) | Value::Int(_) => None,
+            Value::Hll(_) => Some(ValueType::Hll),
+            Value::Bitmap(_) => Some(ValueType::Bitmap),
+            Value::Stream(_) => Some(ValueType::Stream),
+        }
+    }
+
+    // Report the encoding Redis would use for this value
+    fn encoding(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int",
+            Value::Str(s) if s.len() <= EMBSTR_MAX_LEN => "embstr",
+            Value::Str(_) | Value::Hll(_) | Value::Bitmap(_) => "raw",
+            Value::Stream(_) => "stream",
+            Value::Mapped(_) => "mmap",
+        }
+    }
+
+    // Kind of value, as BIGKEYS reports it
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Str(_) | Value::Mapped(_) | Value::Int(_) => "string",
+            Value::Hll(_) => "hyperloglog",
+            Value::Bitmap(_) => "bitmap",
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    // Keys sharing this value's storage; only interned strings are shared
+    fn refcount(&self) -> usize {
+        match self {
+            Value::Str(s) => s.refcount(),
+            _ => 1,
+        }
+    }
+
+    // Add delta to an integer value, failing on non-integers and overflow
+    fn incr_by(&self, delta: i64) -> Result<i64, String> {
+        let current = match self {
+            Value::Int(n) => *n,
+            _ => return Err("ERROR: value is not an integer or out of range".to_string()),
+        };
+        current
+            .checked_add(delta)
+            .ok_or_else(|| "ERROR: increment or decrement would overflow".to_string())
+    }
+}
+
+// A stored value plus its version, bumped on every mutation of the key.
+// A deleted key loses its version and restarts at 1 when recreated.
+#[derive(Debug, Clone)]
+struct Entry {
+    value: Value,
+    version: u64,
+}
+
+// Store a value under key, bumping its version; returns the old value
+fn store_value(map: &mut HashMap<String, Entry>, key: String, value: Value) -> Option<Value> {
+    match map.get_mut(&key) {
+        Some(entry) => {
+            entry.version += 1;
+            Some(std::mem::replace(&mut entry.value, value))
+        }
+        None => {
+            map.insert(key, Entry { value, version: 1 });
+            KEY_CREATED.notify_all();
+            None
+        }
+    }
+}
+
+// A value as a reply to a string read. Streams have no string form, only
+// their WAL encoding, so reading one that way is WRONGTYPE.
+fn string_value(value: &Value) -> Result<String, String> {
+    match value {
+        Value::Stream(_) => Err(WRONGTYPE.to_string()),
+        value => Ok(value.to_string()),
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "{}", s),
+            Value::Mapped(s) => write!(f, "{}", s),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Hll(hll) => write!(f, "{}", hll.encode()),
+            Value::Bitmap(bytes) => write!(f, "{}", bitmap::encode(bytes)),
+            Value::Stream(stream) => write!(f, "{}", stream.encode()),
+        }
+    }
+}
+
+// The write-ahead log. Unit tests append to a scratch file under target/
+// so they never touch a real log.
+#[cfg(not(test))]
+const WAL_PATH: &str = "kvstore.log";
+#[cfg(test)]
+const WAL_PATH: &str = "target/kvstore-test.log";
+
+// Chosen once at startup from --wal-sync-mode
+static WAL_SYNC_MODE: OnceLock<WalSyncMode> = OnceLock::new();
+
+// Commands renamed or disabled by --rename-command, original name to new
+// name ("" if disabled); empty unless the flag is given
+static COMMAND_RENAMES: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+// Settings the server was started with, for CONFIG DUMP
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+// Started at startup when --forward-to is given
+static FORWARDER: OnceLock<Forwarder> = OnceLock::new();
+
+// Grace period from --lame-duck-seconds; None if lame-duck mode is off
+static LAME_DUCK_GRACE: OnceLock<Option<Duration>> = OnceLock::new();
+
+// Cap on HISTORY's count, from --history-max
+static MAX_HISTORY_ENTRIES: OnceLock<usize> = OnceLock::new();
+
+// From --max-connection-age; None lets connections live indefinitely
+static MAX_CONNECTION_AGE: OnceLock<Option<Duration>> = OnceLock::new();
+
+// Set once lame-duck mode starts; INFO then reports the server not ready
+static LAME_DUCK: AtomicBool = AtomicBool::new(false);
+
+// Bulk SETs acknowledged but not yet logged, across all connections. The
+// WAL is only handed over once this drops to zero; from lame-duck mode on,
+// connections flush their loads and log each bulk SET before acking it.
+static UNLOGGED_BULK_SETS: AtomicU64 = AtomicU64::new(0);
+
+// Sequence number of the last logged write; restored by replay so it
+// never goes backward across restarts
+static OP_SEQ: AtomicU64 = AtomicU64::new(0);
+
+// Held from taking sequence numbers until their records are written, so a
+// failed write gives them back instead of leaving a gap, and records land
+// in the WAL in sequence order
+static LOG_APPEND: Mutex<()> = Mutex::new(());
+
+// Where a logged write landed: its sequence number and the WAL size
+// right after it
+#[derive(Debug, Clone, Copy)]
+struct LoggedWrite {
+    seq: u64,
+    offset: u64,
+}
+
+// Commands parsed successfully, by name in COMMAND_NAMES order
+static COMMAND_COUNTS: [AtomicU64; COMMAND_NAMES.len()] =
+    [const { AtomicU64::new(0) }; COMMAND_NAMES.len()];
+
+// Signalled, with the data lock held, whenever a write creates a key.
+// BGET waits on it with that lock and re-checks its own key on each wakeup.
+static KEY_CREATED: Condvar = Condvar::new();
+
+// Client connections currently being served
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // Last WAL write made by this connection's thread while handling the
+    // current command; cleared before each command is read
+    static LOGGED_WRITE: Cell<Option<LoggedWrite>> = const { Cell::new(None) };
+
+    // Set by DURABILITY RELAXED: this connection's writes are acknowledged
+    // once written to the WAL, without waiting for them to reach disk.
+    // Each connection has its own thread, so this is per connection.
+    static RELAXED_DURABILITY: Cell<bool> = const { Cell::new(false) };
 }
 
+// Command names known to the parser, used for typo suggestions
+const COMMAND_NAMES: &[&str] = &[
+    "SET", "GET", "BGET", "GETRANGE", "SETRANGE", "DELETE", "UNSET", "INCR", "DECR", "DECRFLOOR", "CLAMPINCR", "RATELIMIT", "OBJECT", "HISTORY", "BIGKEYS", "DRYRUN", "GETVER", "SETVER",
+    "BULKLOAD", "SWAP", "SWAPKEYS", "INFO", "METRICS", "CONFIG", "CLIENT", "LATENCY", "TIME", "SELFTEST", "SHUTDOWN", "PFADD", "PFCOUNT", "PFMERGE",
+    "OPSEQ", "VERBOSE", "DURABILITY", "MAXREPLY",
+    "SETBIT", "GETBIT", "BITCOUNT", "BITOP", "JSONSET", "JSONGET",
+    "CMPEQ", "AGGREGATE", "SCANVALUE", "LCS", "CYCLE", "INITIF", "GETORSET", "SETIF", "CADEL", "XADD", "XRANGE", "XLEN",
+    "XREAD", "XGROUP", "XREADGROUP", "XACK",
+];
+
+const WRONGTYPE: &str = "ERROR: WRONGTYPE Operation against a key holding the wrong kind of value";
+
+const INVALID_STREAM_ID: &str = "ERROR: Invalid stream ID specified as stream command argument";
+
+const INVALID_CODEC: &str = "ERROR: encoding must be base64 or none";
+
+// Writes refused once a --reuse-port successor owns the WAL
+const READONLY: &str = "ERROR: read-only (lame duck), writes go to the successor";
+
+const WRONGTYPE_HLL: &str = "ERROR: WRONGTYPE Key is not a valid HyperLogLog string value";
+
+// HISTORY defaults and upper bound on entries returned per call
+const DEFAULT_HISTORY_ENTRIES: usize = 10;
+
+// BIGKEYS defaults and upper bound on keys returned per call
+const DEFAULT_BIGKEYS: usize = 10;
+const MAX_BIGKEYS: usize = 1000;
+
+// SETs applied per lock acquisition and fsync during BULKLOAD
+const BULKLOAD_BATCH_SIZE: usize = 1000;
 
-// Replay WAL from disk to rebuild in-memory state
-fn replay_log() -> io::Result<HashMap<String, String>> {
+// Block size used when scanning the WAL backward
+const REVERSE_READ_BLOCK: u64 = 8192;
+
+// Strings up to this length are reported as embstr, matching Redis
+const EMBSTR_MAX_LEN: usize = 44;
+
+// Largest LCS table (product of the two value lengths in characters)
+// computed per call, bounding its O(n*m) time and memory. The table is
+// filled after the data lock is released, so the command time budget
+// does not apply.
+const MAX_LCS_CELLS: usize = 4_000_000;
+
+// Reserved key SELFTEST writes and deletes; it never outlives the lock
+const SELFTEST_KEY: &str = "__selftest__";
+
+// Largest offset SETRANGE accepts (512 MiB values, as in Redis)
+const MAX_SETRANGE_OFFSET: usize = (512 << 20) - 1;
+
+// Keys SCANVALUE examines per call
+const SCANVALUE_BATCH: usize = 100;
+
+// Typos further than this from every known command get no suggestion
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+
+
+// Replay the WAL at path to rebuild in-memory state. With a memory limit,
+// replay keeps a running estimate of the dataset size and fails as soon
+// as it exceeds the limit, rather than running out of memory mid-load.
+fn replay_log(path: &str, max_memory: Option<u64>) -> io::Result<HashMap<String, Entry>> {
     let mut map = HashMap::new();
     
-    let file = match File::open("kvstore.log") {
+    let file = match File::open(path) {
         Ok(f) => f,
         Err(e) if e.kind() == io::ErrorKind::NotFound => {
             return Ok(map);
@@ -29,40 +593,257 @@ fn replay_log() -> io::Result<HashMap<String, String>> {
     };
     
     let reader = BufReader::new(file);
+    let mut estimated_bytes: u64 = 0;
     
     for line in reader.lines() {
         let line = line?;
 
-        let command: Command = match serde_json::from_str(&line) {
-            Ok(cmd) => cmd,
+        let record: LogRecord<Command> = match serde_json::from_str(&line) {
+            Ok(record) => record,
             Err(e) => {
                 eprintln!("Warning: Skipped corrupted log entry: {}", e);
                 continue;
             }
         };
 
-        match command {
-            Command::SET { key, value } => {
-                map.insert(key, value);
-            }
-            Command::DELETE { key } => {
-                map.remove(&key);
-            }
-            Command::GET { .. } => {}
+        if let Some(seq) = record.seq {
+            OP_SEQ.fetch_max(seq, Ordering::Relaxed);
+        }
+
+        let key = record.command.written_key().map(str::to_string);
+        let before = key.as_deref().map_or(0, |k| entry_size(&map, k));
+        replay_command(&mut map, record.command);
+
+        if let Some(key) = key {
+            estimated_bytes = estimated_bytes - before + entry_size(&map, &key);
+        }
+        if let Some(limit) = max_memory
+            && estimated_bytes > limit
+        {
+            return Err(io::Error::other(format!(
+                "dataset exceeds memory limit: more than {} bytes estimated, limit is {}",
+                estimated_bytes, limit
+            )));
         }
     }
     
     Ok(map)
 }
 
+// Apply one logged command to the map during replay
+fn replay_command(map: &mut HashMap<String, Entry>, command: Command) {
+    match command {
+        Command::SET { key, value, kind, .. } => {
+            store_value(map, key, Value::from_logged(value, kind));
+        }
+        Command::SNAPSHOT { key, value, kind, version } => {
+            map.insert(key, Entry { value: Value::from_logged(value, kind), version });
+        }
+        Command::DELETE { key } => {
+            map.remove(&key);
+        }
+        Command::PFADD { key, elements } => {
+            if let Ok(Some(hll)) = pfadd_value(map.get(&key).map(|e| &e.value), &elements) {
+                store_value(map, key, Value::Hll(hll));
+            }
+        }
+        Command::PFMERGE { dest, sources } => {
+            if let Ok(hll) = pfmerge_value(map, &dest, &sources) {
+                store_value(map, dest, Value::Hll(hll));
+            }
+        }
+        Command::SETBIT { key, offset, bit } => {
+            let _ = setbit_in_map(map, key, offset, bit);
+        }
+        Command::BITOP { op, dest, sources } => {
+            if let Ok(result) = bitop_value(map, op, &sources) {
+                store_bitop_result(map, dest, result);
+            }
+        }
+        Command::XADD { key, id: Some(id), fields } => {
+            let _ = xadd_in_map(map, key, id, fields);
+        }
+        Command::XGROUP { key, group, id: Some(id) } => {
+            if let Some(stream) = stream_mut(map, &key) {
+                stream.create_group(group, id);
+            }
+        }
+        Command::XREADGROUP { group, consumer, key, id: None, count } => {
+            if let Some(stream) = stream_mut(map, &key) {
+                stream.deliver(&group, &consumer, count);
+            }
+        }
+        Command::XACK { key, group, ids } => {
+            if let Some(stream) = stream_mut(map, &key) {
+                stream.ack(&group, &ids);
+            }
+        }
+        Command::LASTSEQ { seq } => {
+            OP_SEQ.fetch_max(seq, Ordering::Relaxed);
+        }
+        // UNSET and CADEL are logged as DELETE; counters, DECRFLOOR, CLAMPINCR,
+        // RATELIMIT, SETRANGE, SETVER, SWAP, JSONSET, CYCLE, INITIF and
+        // GETORSET as a SET of the resulting value; SETIF as one SET per key; SWAPKEYS as a
+        // SET or DELETE per key
+        Command::GET { .. }
+        | Command::BGET { .. }
+        | Command::GETRANGE { .. }
+        | Command::SETRANGE { .. }
+        | Command::UNSET { .. }
+        | Command::CADEL { .. }
+        | Command::GETVER { .. }
+        | Command::SETVER { .. }
+        | Command::INCR { .. }
+        | Command::DECR { .. }
+        | Command::DECRFLOOR { .. }
+        | Command::CLAMPINCR { .. }
+        | Command::RATELIMIT { .. }
+        | Command::SWAP { .. }
+        | Command::SWAPKEYS { .. }
+        | Command::INFO
+        | Command::METRICS
+        | Command::CONFIG { .. }
+        | Command::CLIENT { .. }
+        | Command::LATENCY { .. }
+        | Command::TIME
+        | Command::SELFTEST
+        | Command::SHUTDOWN
+        | Command::PFCOUNT { .. }
+        | Command::GETBIT { .. }
+        | Command::BITCOUNT { .. }
+        | Command::JSONSET { .. }
+        | Command::JSONGET { .. }
+        | Command::CMPEQ { .. }
+        | Command::AGGREGATE { .. }
+        | Command::SCANVALUE { .. }
+        | Command::LCS { .. }
+        | Command::CYCLE { .. }
+        | Command::INITIF { .. }
+        | Command::GETORSET { .. }
+        | Command::SETIF { .. }
+        | Command::XADD { id: None, .. }
+        | Command::XRANGE { .. }
+        | Command::XLEN { .. }
+        | Command::XREAD { .. }
+        | Command::XGROUP { id: None, .. }
+        | Command::XREADGROUP { id: Some(_), .. }
+        | Command::BULKLOAD { .. }
+        | Command::OBJECT { .. }
+        | Command::HISTORY { .. }
+        | Command::BIGKEYS { .. }
+        | Command::DRYRUN { .. }
+        | Command::OPSEQ { .. }
+        | Command::VERBOSE { .. }
+        | Command::DURABILITY { .. }
+        | Command::MAXREPLY { .. } => {}
+    }
+}
+
+// Rough in-memory footprint of one key: key and value bytes plus a fixed
+// allowance for the map slot and allocations
+fn entry_size(map: &HashMap<String, Entry>, key: &str) -> u64 {
+    const ENTRY_OVERHEAD: u64 = 64;
+
+    map.get(key).map_or(0, |entry| {
+        let value_bytes = match &entry.value {
+            Value::Str(s) => s.len(),
+            // Only the handle is on the heap; the bytes live in the mapped file
+            Value::Mapped(_) => 16,
+            Value::Int(_) => 8,
+            Value::Hll(_) => hll::REGISTERS,
+            Value::Bitmap(bytes) => bytes.len(),
+            Value::Stream(stream) => stream.size(),
+        };
+        ENTRY_OVERHEAD + key.len() as u64 + value_bytes as u64
+    })
+}
+
+// Reads WAL records newest-first by scanning fixed-size blocks from the end
+struct ReverseLogReader {
+    file: File,
+    pos: u64,           // Start of the region not yet read
+    carry: Vec<u8>,     // Head of a line split across a block boundary
+    lines: Vec<String>, // Complete lines from the last block, in file order
+}
+
+impl ReverseLogReader {
+    fn open(path: &str) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let pos = file.metadata()?.len();
+        Ok(ReverseLogReader {
+            file,
+            pos,
+            carry: Vec::new(),
+            lines: Vec::new(),
+        })
+    }
+
+    fn next_line(&mut self) -> io::Result<Option<String>> {
+        loop {
+            if let Some(line) = self.lines.pop() {
+                return Ok(Some(line));
+            }
+
+            // Whatever is carried at the start of the file is the first line
+            if self.pos == 0 {
+                if self.carry.is_empty() {
+                    return Ok(None);
+                }
+                let line = std::mem::take(&mut self.carry);
+                return Ok(Some(String::from_utf8_lossy(&line).into_owned()));
+            }
+
+            let start = self.pos.saturating_sub(REVERSE_READ_BLOCK);
+            let mut block = vec![0; (self.pos - start) as usize];
+            self.file.seek(SeekFrom::Start(start))?;
+            self.file.read_exact(&mut block)?;
+            self.pos = start;
+            block.extend_from_slice(&self.carry);
+
+            // The first segment may continue into the previous block
+            let mut segments = block.split(|b| *b == b'\n');
+            self.carry = segments.next().unwrap_or_default().to_vec();
+            self.lines = segments
+                .filter(|segment| !segment.is_empty())
+                .map(|segment| String::from_utf8_lossy(segment).into_owned())
+                .collect();
+        }
+    }
+}
+
+impl Iterator for ReverseLogReader {
+    type Item = io::Result<LogRecord<Command>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.next_line() {
+                Ok(Some(line)) => line,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            };
+
+            match serde_json::from_str(&line) {
+                Ok(record) => return Some(Ok(record)),
+                Err(e) => eprintln!("Warning: Skipped corrupted log entry: {}", e),
+            }
+        }
+    }
+}
+
 // Compact WAL by rewriting only current state
-fn compact_log(map: &HashMap<String, String>) -> io::Result<()> {
-    let mut temp = File::create("kvstore.log.tmp")?;
+fn compact_log(map: &HashMap<String, Entry>) -> io::Result<()> {
+    let mut temp = File::create(format!("{}.tmp", WAL_PATH))?;
+
+    let last = Command::LASTSEQ { seq: OP_SEQ.load(Ordering::Relaxed) };
+    serde_json::to_writer(&mut temp, &last)?;
+    temp.write_all(b"\n")?;
     
-    for (key, value) in map {
-        let cmd = Command::SET { 
+    for (key, entry) in map {
+        let cmd = Command::SNAPSHOT { 
             key: key.clone(), 
-            value: value.clone() 
+            value: entry.value.to_string(),
+            kind: entry.value.kind(),
+            version: entry.version,
         };
         let json = serde_json::to_string(&cmd)?;
         temp.write_all(json.as_bytes())?;
@@ -70,129 +851,2972 @@ fn compact_log(map: &HashMap<String, String>) -> io::Result<()> {
     }
     
     temp.sync_all()?;
-    std::fs::rename("kvstore.log.tmp", "kvstore.log")?;
+    std::fs::rename(format!("{}.tmp", WAL_PATH), WAL_PATH)?;
     
     Ok(())
 }
 
 
-fn parse_command(input: &str) -> Result<Command, String> {
-    let parts: Vec<&str> = input.split_whitespace().collect();
-    
-    if parts.is_empty() {
-        return Err("ERROR: Empty command".to_string());
-    }
-    
-    let cmd = parts[0].to_uppercase();
-    
-    match (cmd.as_str(), parts.len()) {
-        ("SET", 3) => Ok(Command::SET {
-            key: parts[1].to_string(),
-            value: parts[2].to_string(),
-        }),
-        ("SET", _) => Err("ERROR: SET requires a key and value".to_string()),
-        
-        ("GET", 2) => Ok(Command::GET {
-            key: parts[1].to_string(),
-        }),
-        ("GET", _) => Err("ERROR: GET requires a key".to_string()),
-        
-        ("DELETE", 2) => Ok(Command::DELETE {
-            key: parts[1].to_string(),
-        }),
-        ("DELETE", _) => Err("ERROR: DELETE requires a key".to_string()),
-        
-        _ => Err("ERROR: Unknown command".to_string()),
-    }
-}
+// Edit distance between two strings (insert, delete, substitute)
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut curr = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            curr[j + 1] = (prev[j] + cost)
+                .min(prev[j + 1] + 1)
+                .min(curr[j] + 1);
+        }
+        prev = curr;
+    }
+
+    prev[b.len()]
+}
+
+fn renamed_commands() -> &'static HashMap<String, String> {
+    COMMAND_RENAMES.get_or_init(HashMap::new)
+}
+
+// Name a command is dispatched under: a new name from --rename-command
+// maps back to the original, and a renamed or disabled original is
+// unknown (None)
+fn resolve_command_name(name: &str) -> Option<String> {
+    resolve_renamed(renamed_commands(), name)
+}
+
+fn resolve_renamed(renames: &HashMap<String, String>, name: &str) -> Option<String> {
+    let upper = name.to_uppercase();
+    if let Some((original, _)) = renames.iter().find(|(_, new_name)| **new_name == upper) {
+        return Some(original.clone());
+    }
+    (!renames.contains_key(&upper)).then_some(upper)
+}
+
+// Check --rename-command pairs against the command table and install them
+fn install_command_renames(renames: &[(String, String)]) -> Result<(), String> {
+    let table = rename_table(renames)?;
+    let _ = COMMAND_RENAMES.set(table);
+    Ok(())
+}
+
+// Originals must be known commands and new names must not clash with
+// other commands or each other
+fn rename_table(renames: &[(String, String)]) -> Result<HashMap<String, String>, String> {
+    let mut table = HashMap::new();
+    for (original, new_name) in renames {
+        if !COMMAND_NAMES.contains(&original.as_str()) {
+            return Err(format!("ERROR: --rename-command: unknown command '{}'", original));
+        }
+        table.insert(original.clone(), new_name.clone());
+    }
+    for (original, new_name) in &table {
+        let clashes_with_command = COMMAND_NAMES.contains(&new_name.as_str()) && !table.contains_key(new_name);
+        let clashes_with_rename = table.iter().any(|(o, n)| o != original && n == new_name);
+        if !new_name.is_empty() && (clashes_with_command || clashes_with_rename) {
+            return Err(format!("ERROR: --rename-command: name '{}' is already in use", new_name));
+        }
+    }
+    Ok(table)
+}
+
+// Build the unknown-command error, suggesting the closest known command
+fn unknown_command_error(name: &str) -> String {
+    let upper = name.to_uppercase();
+    let closest = COMMAND_NAMES
+        .iter()
+        .filter(|known| !renamed_commands().contains_key(**known))
+        .map(|known| (levenshtein(&upper, known), *known))
+        .min_by_key(|(distance, _)| *distance);
+
+    match closest {
+        Some((distance, known)) if distance <= MAX_SUGGESTION_DISTANCE => {
+            format!("ERROR: unknown command '{}', did you mean '{}'?", name, known)
+        }
+        _ => "ERROR: Unknown command".to_string(),
+    }
+}
+
+// Parse a line, splitting a trailing IDEMPOTENT <token> off a write
+// command; the token is returned with the normalized command line it
+// came with. If the rest is not a write the whole line is parsed as
+// usual, so a value may still be the word IDEMPOTENT.
+fn parse_with_token(input: &str) -> (Result<Command, String>, Option<(String, String)>) {
+    let parts: Vec<&str> = input.split_whitespace().collect();
+    if let [rest @ .., marker, token] = parts.as_slice()
+        && marker.eq_ignore_ascii_case("IDEMPOTENT")
+    {
+        let request = rest.join(" ");
+        if let Ok(command) = parse_command(&request)
+            && command.is_write()
+        {
+            return (Ok(command), Some((token.to_string(), request)));
+        }
+    }
+    (parse_command(input), None)
+}
+
+fn parse_command(input: &str) -> Result<Command, String> {
+    let parts: Vec<&str> = input.split_whitespace().collect();
+    
+    if parts.is_empty() {
+        return Err("ERROR: Empty command".to_string());
+    }
+    
+    let Some(cmd) = resolve_command_name(parts[0]) else {
+        return Err(unknown_command_error(parts[0]));
+    };
+    
+    match (cmd.as_str(), parts.len()) {
+        ("SET", 3) => Ok(Command::SET {
+            key: parts[1].to_string(),
+            value: parts[2].to_string(),
+            kind: None,
+            get: false,
+        }),
+        ("SET", 4) if parts[3].eq_ignore_ascii_case("GET") => Ok(Command::SET {
+            key: parts[1].to_string(),
+            value: parts[2].to_string(),
+            kind: None,
+            get: true,
+        }),
+        ("SET", 4) => Err("ERROR: SET option must be GET".to_string()),
+        // The value is stored and logged already encoded
+        ("SET", 5) if parts[3].eq_ignore_ascii_case("ENCODE") => match Codec::parse(parts[4]) {
+            Some(codec) => Ok(Command::SET {
+                key: parts[1].to_string(),
+                value: codec.encode(parts[2]),
+                kind: None,
+                get: false,
+            }),
+            None => Err(INVALID_CODEC.to_string()),
+        },
+        ("SET", _) => Err("ERROR: SET requires a key and value".to_string()),
+        
+        ("GET", 2) => Ok(Command::GET {
+            key: parts[1].to_string(),
+            decode: Codec::None,
+        }),
+        ("GET", 4) if parts[2].eq_ignore_ascii_case("DECODE") => match Codec::parse(parts[3]) {
+            Some(decode) => Ok(Command::GET { key: parts[1].to_string(), decode }),
+            None => Err(INVALID_CODEC.to_string()),
+        },
+        ("GET", _) => Err("ERROR: GET requires a key and optional DECODE encoding".to_string()),
+
+        // Timeout in seconds, fractions allowed; 0 waits indefinitely
+        ("BGET", 3) => match parts[2].parse::<f64>() {
+            Ok(secs) if secs.is_finite() && secs >= 0.0 => Ok(Command::BGET {
+                key: parts[1].to_string(),
+                timeout: (secs > 0.0).then(|| Duration::from_secs_f64(secs)),
+            }),
+            _ => Err("ERROR: BGET timeout must be a non-negative number of seconds".to_string()),
+        },
+        ("BGET", _) => Err("ERROR: BGET requires a key and a timeout".to_string()),
+
+        ("GETRANGE", 4) => match (parts[2].parse::<i64>(), parts[3].parse::<i64>()) {
+            (Ok(start), Ok(end)) => Ok(Command::GETRANGE {
+                key: parts[1].to_string(),
+                start,
+                end,
+            }),
+            _ => Err("ERROR: GETRANGE offsets must be integers".to_string()),
+        },
+        ("GETRANGE", _) => Err("ERROR: GETRANGE requires a key, start and end".to_string()),
+
+        ("SETRANGE", 4) => match parts[2].parse::<usize>() {
+            Ok(offset) if offset <= MAX_SETRANGE_OFFSET => Ok(Command::SETRANGE {
+                key: parts[1].to_string(),
+                offset,
+                value: parts[3].to_string(),
+            }),
+            _ => Err("ERROR: offset is out of range".to_string()),
+        },
+        ("SETRANGE", _) => Err("ERROR: SETRANGE requires a key, offset and value".to_string()),
+        
+        ("DELETE", 2) => Ok(Command::DELETE {
+            key: parts[1].to_string(),
+        }),
+        ("DELETE", _) => Err("ERROR: DELETE requires a key".to_string()),
+
+        ("UNSET", 2) => Ok(Command::UNSET {
+            key: parts[1].to_string(),
+        }),
+        ("UNSET", _) => Err("ERROR: UNSET requires a key".to_string()),
+
+        ("INCR", 2) => Ok(Command::INCR {
+            key: parts[1].to_string(),
+        }),
+        ("INCR", _) => Err("ERROR: INCR requires a key".to_string()),
+
+        ("DECR", 2) => Ok(Command::DECR {
+            key: parts[1].to_string(),
+        }),
+        ("DECR", _) => Err("ERROR: DECR requires a key".to_string()),
+
+        ("DECRFLOOR", 3) => match parts[2].parse::<i64>() {
+            Ok(amount) if amount >= 0 => Ok(Command::DECRFLOOR {
+                key: parts[1].to_string(),
+                amount,
+            }),
+            _ => Err("ERROR: DECRFLOOR amount must be a non-negative integer".to_string()),
+        },
+        ("DECRFLOOR", _) => Err("ERROR: DECRFLOOR requires a key and amount".to_string()),
+
+        ("CLAMPINCR", 5) => match (parts[2].parse::<i64>(), parts[3].parse::<i64>(), parts[4].parse::<i64>()) {
+            (Ok(delta), Ok(min), Ok(max)) if min <= max => Ok(Command::CLAMPINCR {
+                key: parts[1].to_string(),
+                delta,
+                min,
+                max,
+            }),
+            (Ok(_), Ok(_), Ok(_)) => Err("ERROR: CLAMPINCR min must not exceed max".to_string()),
+            _ => Err("ERROR: CLAMPINCR delta, min and max must be integers".to_string()),
+        },
+        ("CLAMPINCR", _) => Err("ERROR: CLAMPINCR requires a key, delta, min and max".to_string()),
+
+        ("RATELIMIT", 4) => match (parts[2].parse::<u64>(), parts[3].parse::<u64>()) {
+            (Ok(limit), Ok(window)) if window > 0 => Ok(Command::RATELIMIT {
+                key: parts[1].to_string(),
+                limit,
+                window,
+            }),
+            _ => Err("ERROR: RATELIMIT limit and window must be integers, window positive".to_string()),
+        },
+        ("RATELIMIT", _) => Err("ERROR: RATELIMIT requires a key, limit and window in seconds".to_string()),
+
+        ("OBJECT", 3) if parts[1].eq_ignore_ascii_case("ENCODING") => Ok(Command::OBJECT {
+            subcommand: ObjectSubcommand::Encoding,
+            key: parts[2].to_string(),
+        }),
+        ("OBJECT", 3) if parts[1].eq_ignore_ascii_case("REFCOUNT") => Ok(Command::OBJECT {
+            subcommand: ObjectSubcommand::Refcount,
+            key: parts[2].to_string(),
+        }),
+        ("OBJECT", _) => Err("ERROR: OBJECT requires ENCODING or REFCOUNT and a key".to_string()),
+
+        ("HISTORY", 2) => Ok(Command::HISTORY {
+            key: parts[1].to_string(),
+            count: DEFAULT_HISTORY_ENTRIES,
+        }),
+        ("HISTORY", 3) => match parts[2].parse::<usize>() {
+            Ok(count) if count > 0 => Ok(Command::HISTORY {
+                key: parts[1].to_string(),
+                count,
+            }),
+            _ => Err("ERROR: HISTORY count must be a positive integer".to_string()),
+        },
+        ("HISTORY", _) => Err("ERROR: HISTORY requires a key and optional count".to_string()),
+
+        ("BIGKEYS", 1) => Ok(Command::BIGKEYS { count: DEFAULT_BIGKEYS }),
+        ("BIGKEYS", 2) => match parts[1].parse::<usize>() {
+            Ok(count) if count > 0 => Ok(Command::BIGKEYS { count: count.min(MAX_BIGKEYS) }),
+            _ => Err("ERROR: BIGKEYS count must be a positive integer".to_string()),
+        },
+        ("BIGKEYS", _) => Err("ERROR: BIGKEYS takes an optional count".to_string()),
+
+        ("DRYRUN", 2) => match parts[1].to_uppercase().as_str() {
+            "ON" => Ok(Command::DRYRUN { enabled: true }),
+            "OFF" => Ok(Command::DRYRUN { enabled: false }),
+            _ => Err("ERROR: DRYRUN requires ON or OFF".to_string()),
+        },
+        ("DRYRUN", _) => Err("ERROR: DRYRUN requires ON or OFF".to_string()),
+
+        ("OPSEQ", 2) => match parts[1].to_uppercase().as_str() {
+            "ON" => Ok(Command::OPSEQ { enabled: true }),
+            "OFF" => Ok(Command::OPSEQ { enabled: false }),
+            _ => Err("ERROR: OPSEQ requires ON or OFF".to_string()),
+        },
+        ("OPSEQ", _) => Err("ERROR: OPSEQ requires ON or OFF".to_string()),
+
+        ("VERBOSE", 2) => match parts[1].to_uppercase().as_str() {
+            "ON" => Ok(Command::VERBOSE { enabled: true }),
+            "OFF" => Ok(Command::VERBOSE { enabled: false }),
+            _ => Err("ERROR: VERBOSE requires ON or OFF".to_string()),
+        },
+        ("VERBOSE", _) => Err("ERROR: VERBOSE requires ON or OFF".to_string()),
+
+        ("DURABILITY", 2) => match parts[1].to_uppercase().as_str() {
+            "STRICT" => Ok(Command::DURABILITY { relaxed: false }),
+            "RELAXED" => Ok(Command::DURABILITY { relaxed: true }),
+            _ => Err("ERROR: DURABILITY requires STRICT or RELAXED".to_string()),
+        },
+        ("DURABILITY", _) => Err("ERROR: DURABILITY requires STRICT or RELAXED".to_string()),
+
+        ("MAXREPLY", 2) => match parts[1].parse::<usize>() {
+            Ok(bytes) => Ok(Command::MAXREPLY { bytes }),
+            Err(_) => Err("ERROR: MAXREPLY size must be a non-negative integer".to_string()),
+        },
+        ("MAXREPLY", _) => Err("ERROR: MAXREPLY requires a size in bytes".to_string()),
+
+        ("GETVER", 2) => Ok(Command::GETVER {
+            key: parts[1].to_string(),
+        }),
+        ("GETVER", _) => Err("ERROR: GETVER requires a key".to_string()),
+
+        ("SETVER", 4) => match parts[3].parse::<u64>() {
+            Ok(expected) => Ok(Command::SETVER {
+                key: parts[1].to_string(),
+                value: parts[2].to_string(),
+                expected,
+            }),
+            Err(_) => Err("ERROR: SETVER version must be a non-negative integer".to_string()),
+        },
+        ("SETVER", _) => Err("ERROR: SETVER requires a key, value and expected version".to_string()),
+
+        ("SWAP", 3) => Ok(Command::SWAP {
+            key: parts[1].to_string(),
+            value: parts[2].to_string(),
+        }),
+        ("SWAP", _) => Err("ERROR: SWAP requires a key and new value".to_string()),
+
+        ("SWAPKEYS", 3) => Ok(Command::SWAPKEYS {
+            key1: parts[1].to_string(),
+            key2: parts[2].to_string(),
+        }),
+        ("SWAPKEYS", _) => Err("ERROR: SWAPKEYS requires two keys".to_string()),
+
+        ("PFADD", n) if n >= 2 => Ok(Command::PFADD {
+            key: parts[1].to_string(),
+            elements: parts[2..].iter().map(|s| s.to_string()).collect(),
+        }),
+        ("PFADD", _) => Err("ERROR: PFADD requires a key and optional elements".to_string()),
+
+        ("PFCOUNT", n) if n >= 2 => Ok(Command::PFCOUNT {
+            keys: parts[1..].iter().map(|s| s.to_string()).collect(),
+        }),
+        ("PFCOUNT", _) => Err("ERROR: PFCOUNT requires at least one key".to_string()),
+
+        ("PFMERGE", n) if n >= 2 => Ok(Command::PFMERGE {
+            dest: parts[1].to_string(),
+            sources: parts[2..].iter().map(|s| s.to_string()).collect(),
+        }),
+        ("PFMERGE", _) => Err("ERROR: PFMERGE requires a destination and source keys".to_string()),
+
+        ("SETBIT", 4) => {
+            let offset = match parts[2].parse::<u64>() {
+                Ok(offset) if offset <= bitmap::MAX_BIT_OFFSET => offset,
+                _ => return Err("ERROR: bit offset is not an integer or out of range".to_string()),
+            };
+            let bit = match parts[3] {
+                "0" => false,
+                "1" => true,
+                _ => return Err("ERROR: bit is not an integer or out of range".to_string()),
+            };
+            Ok(Command::SETBIT {
+                key: parts[1].to_string(),
+                offset,
+                bit,
+            })
+        }
+        ("SETBIT", _) => Err("ERROR: SETBIT requires a key, offset and bit".to_string()),
+
+        ("GETBIT", 3) => match parts[2].parse::<u64>() {
+            Ok(offset) => Ok(Command::GETBIT {
+                key: parts[1].to_string(),
+                offset,
+            }),
+            Err(_) => Err("ERROR: bit offset is not an integer or out of range".to_string()),
+        },
+        ("GETBIT", _) => Err("ERROR: GETBIT requires a key and offset".to_string()),
+
+        ("BITCOUNT", 2) => Ok(Command::BITCOUNT {
+            key: parts[1].to_string(),
+            range: None,
+        }),
+        ("BITCOUNT", 4) => match (parts[2].parse::<i64>(), parts[3].parse::<i64>()) {
+            (Ok(start), Ok(end)) => Ok(Command::BITCOUNT {
+                key: parts[1].to_string(),
+                range: Some((start, end)),
+            }),
+            _ => Err("ERROR: BITCOUNT range is not an integer".to_string()),
+        },
+        ("BITCOUNT", _) => Err("ERROR: BITCOUNT requires a key and optional start and end".to_string()),
+
+        ("BITOP", n) if n >= 4 => {
+            let op = match parts[1].to_uppercase().as_str() {
+                "AND" => BitOp::And,
+                "OR" => BitOp::Or,
+                "XOR" => BitOp::Xor,
+                "NOT" => BitOp::Not,
+                _ => return Err("ERROR: BITOP operation must be AND, OR, XOR or NOT".to_string()),
+            };
+            if op == BitOp::Not && n != 4 {
+                return Err("ERROR: BITOP NOT must be called with a single source key".to_string());
+            }
+            Ok(Command::BITOP {
+                op,
+                dest: parts[2].to_string(),
+                sources: parts[3..].iter().map(|s| s.to_string()).collect(),
+            })
+        }
+        ("BITOP", _) => Err("ERROR: BITOP requires an operation, a destination and source keys".to_string()),
+
+        ("JSONSET", 4) => Ok(Command::JSONSET {
+            key: parts[1].to_string(),
+            path: parts[2].to_string(),
+            value: parts[3].to_string(),
+        }),
+        ("JSONSET", _) => Err("ERROR: JSONSET requires a key, path and JSON value".to_string()),
+
+        ("JSONGET", 2) => Ok(Command::JSONGET {
+            key: parts[1].to_string(),
+            path: ".".to_string(),
+        }),
+        ("JSONGET", 3) => Ok(Command::JSONGET {
+            key: parts[1].to_string(),
+            path: parts[2].to_string(),
+        }),
+        ("JSONGET", _) => Err("ERROR: JSONGET requires a key and optional path".to_string()),
+
+        ("SCANVALUE", 3) => match scanvalue_cursor(parts[1]) {
+            Some(cursor) => Ok(Command::SCANVALUE {
+                cursor,
+                pattern: parts[2].to_string(),
+            }),
+            None => Err("ERROR: invalid SCANVALUE cursor".to_string()),
+        },
+        ("SCANVALUE", _) => Err("ERROR: SCANVALUE requires a cursor and a pattern".to_string()),
+
+        ("CMPEQ", 3) => Ok(Command::CMPEQ {
+            key1: parts[1].to_string(),
+            key2: parts[2].to_string(),
+        }),
+        ("CMPEQ", _) => Err("ERROR: CMPEQ requires two keys".to_string()),
+
+        ("AGGREGATE", n) if n >= 3 => {
+            let op = match parts[1].to_uppercase().as_str() {
+                "SUM" => AggregateOp::Sum,
+                "MIN" => AggregateOp::Min,
+                "MAX" => AggregateOp::Max,
+                "AVG" => AggregateOp::Avg,
+                _ => return Err("ERROR: AGGREGATE operation must be SUM, MIN, MAX or AVG".to_string()),
+            };
+            Ok(Command::AGGREGATE {
+                op,
+                keys: parts[2..].iter().map(|s| s.to_string()).collect(),
+            })
+        }
+        ("AGGREGATE", _) => Err("ERROR: AGGREGATE requires an operation and at least one key".to_string()),
+
+        ("LCS", 3) => Ok(Command::LCS {
+            key1: parts[1].to_string(),
+            key2: parts[2].to_string(),
+            len_only: false,
+        }),
+        ("LCS", 4) if parts[3].eq_ignore_ascii_case("LEN") => Ok(Command::LCS {
+            key1: parts[1].to_string(),
+            key2: parts[2].to_string(),
+            len_only: true,
+        }),
+        ("LCS", _) => Err("ERROR: LCS requires two keys and optional LEN".to_string()),
+
+        ("CYCLE", n) if n >= 3 => Ok(Command::CYCLE {
+            key: parts[1].to_string(),
+            ring: parts[2..].iter().map(|s| s.to_string()).collect(),
+        }),
+        ("CYCLE", _) => Err("ERROR: CYCLE requires a key and at least one value".to_string()),
+
+        ("INITIF", 3) => Ok(Command::INITIF {
+            key: parts[1].to_string(),
+            value: parts[2].to_string(),
+        }),
+        ("INITIF", _) => Err("ERROR: INITIF requires a key and value".to_string()),
+
+        ("GETORSET", 3) => Ok(Command::GETORSET {
+            key: parts[1].to_string(),
+            default: parts[2].to_string(),
+        }),
+        // Keys never expire here, so there is no TTL to set
+        ("GETORSET", 5) if parts[3].eq_ignore_ascii_case("EX") => {
+            Err("ERROR: GETORSET EX is not supported, keys do not expire".to_string())
+        }
+        ("GETORSET", _) => Err("ERROR: GETORSET requires a key and default value".to_string()),
+
+        ("CADEL", 3) => Ok(Command::CADEL {
+            key: parts[1].to_string(),
+            expected: parts[2].to_string(),
+        }),
+        ("CADEL", _) => Err("ERROR: CADEL requires a key and expected value".to_string()),
+
+        ("SETIF", n) if n >= 5 && n % 2 == 1 => Ok(Command::SETIF {
+            cond_key: parts[1].to_string(),
+            expected: parts[2].to_string(),
+            pairs: parts[3..]
+                .chunks(2)
+                .map(|pair| (pair[0].to_string(), pair[1].to_string()))
+                .collect(),
+        }),
+        ("SETIF", _) => {
+            Err("ERROR: SETIF requires a condition key, expected value and key value pairs".to_string())
+        }
+
+        ("XADD", n) if n >= 5 && n % 2 == 1 => {
+            let id = match parts[2] {
+                "*" => None,
+                id => match StreamId::parse(id, 0) {
+                    Some(id) => Some(id),
+                    None => return Err(INVALID_STREAM_ID.to_string()),
+                },
+            };
+            Ok(Command::XADD {
+                key: parts[1].to_string(),
+                id,
+                fields: parts[3..]
+                    .chunks(2)
+                    .map(|pair| (pair[0].to_string(), pair[1].to_string()))
+                    .collect(),
+            })
+        }
+        ("XADD", _) => Err("ERROR: XADD requires a key, an ID and field value pairs".to_string()),
+
+        // '-' and '+' are the lowest and highest IDs; a bare ms covers
+        // every sequence number in that millisecond
+        ("XRANGE", 4) => {
+            let start = match parts[2] {
+                "-" => Some(StreamId::MIN),
+                id => StreamId::parse(id, 0),
+            };
+            let end = match parts[3] {
+                "+" => Some(StreamId::MAX),
+                id => StreamId::parse(id, u64::MAX),
+            };
+            match (start, end) {
+                (Some(start), Some(end)) => Ok(Command::XRANGE {
+                    key: parts[1].to_string(),
+                    start,
+                    end,
+                }),
+                _ => Err(INVALID_STREAM_ID.to_string()),
+            }
+        }
+        ("XRANGE", _) => Err("ERROR: XRANGE requires a key, start and end".to_string()),
+
+        ("XLEN", 2) => Ok(Command::XLEN {
+            key: parts[1].to_string(),
+        }),
+        ("XLEN", _) => Err("ERROR: XLEN requires a key".to_string()),
+
+        ("XREAD", 3 | 4) => match StreamId::parse(parts[2], 0) {
+            Some(after) => Ok(Command::XREAD {
+                key: parts[1].to_string(),
+                after,
+                count: parse_stream_count(parts.get(3))?,
+            }),
+            None => Err(INVALID_STREAM_ID.to_string()),
+        },
+        ("XREAD", _) => Err("ERROR: XREAD requires a key, an ID and optional count".to_string()),
+
+        ("XGROUP", 5) if parts[1].eq_ignore_ascii_case("CREATE") => {
+            let id = match parts[4] {
+                "$" => None,
+                id => Some(StreamId::parse(id, 0).ok_or_else(|| INVALID_STREAM_ID.to_string())?),
+            };
+            Ok(Command::XGROUP {
+                key: parts[2].to_string(),
+                group: parts[3].to_string(),
+                id,
+            })
+        }
+        ("XGROUP", _) => Err("ERROR: XGROUP requires CREATE, a key, a group and an ID".to_string()),
+
+        ("XREADGROUP", 5 | 6) => {
+            let id = match parts[4] {
+                ">" => None,
+                id => Some(StreamId::parse(id, 0).ok_or_else(|| INVALID_STREAM_ID.to_string())?),
+            };
+            Ok(Command::XREADGROUP {
+                group: parts[1].to_string(),
+                consumer: parts[2].to_string(),
+                key: parts[3].to_string(),
+                id,
+                count: parse_stream_count(parts.get(5))?,
+            })
+        }
+        ("XREADGROUP", _) => {
+            Err("ERROR: XREADGROUP requires a group, a consumer, a key, an ID and optional count".to_string())
+        }
+
+        ("XACK", n) if n >= 4 => Ok(Command::XACK {
+            key: parts[1].to_string(),
+            group: parts[2].to_string(),
+            ids: parts[3..]
+                .iter()
+                .map(|id| StreamId::parse(id, 0))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| INVALID_STREAM_ID.to_string())?,
+        }),
+        ("XACK", _) => Err("ERROR: XACK requires a key, a group and at least one ID".to_string()),
+
+        ("INFO", 1) => Ok(Command::INFO),
+        ("INFO", _) => Err("ERROR: INFO takes no arguments".to_string()),
+
+        ("LATENCY", 2) if parts[1].eq_ignore_ascii_case("HISTORY") => Ok(Command::LATENCY {
+            subcommand: LatencySubcommand::History,
+        }),
+        ("LATENCY", _) => Err("ERROR: LATENCY requires HISTORY".to_string()),
+
+        ("CLIENT", 3) if parts[1].eq_ignore_ascii_case("TRACKING") => match parts[2].to_uppercase().as_str() {
+            "ON" => Ok(Command::CLIENT { subcommand: ClientSubcommand::Tracking { enabled: true } }),
+            "OFF" => Ok(Command::CLIENT { subcommand: ClientSubcommand::Tracking { enabled: false } }),
+            _ => Err("ERROR: CLIENT TRACKING requires ON or OFF".to_string()),
+        },
+        ("CLIENT", _) => Err("ERROR: CLIENT requires TRACKING ON or OFF".to_string()),
+
+        ("CONFIG", 2) if parts[1].eq_ignore_ascii_case("DUMP") => Ok(Command::CONFIG {
+            subcommand: ConfigSubcommand::Dump,
+        }),
+        ("CONFIG", _) => Err("ERROR: CONFIG requires DUMP".to_string()),
+
+        ("METRICS", 1) => Ok(Command::METRICS),
+        ("METRICS", _) => Err("ERROR: METRICS takes no arguments".to_string()),
+
+        ("TIME", 1) => Ok(Command::TIME),
+        ("TIME", _) => Err("ERROR: TIME takes no arguments".to_string()),
+
+        ("SELFTEST", 1) => Ok(Command::SELFTEST),
+        ("SELFTEST", _) => Err("ERROR: SELFTEST takes no arguments".to_string()),
+
+        ("SHUTDOWN", 2) if parts[1].eq_ignore_ascii_case("LAMEDUCK") => Ok(Command::SHUTDOWN),
+        ("SHUTDOWN", _) => Err("ERROR: SHUTDOWN requires LAMEDUCK".to_string()),
+
+        ("BULKLOAD", 2) => match parts[1].to_uppercase().as_str() {
+            "BEGIN" => Ok(Command::BULKLOAD { phase: BulkPhase::Begin }),
+            "END" => Ok(Command::BULKLOAD { phase: BulkPhase::End }),
+            _ => Err("ERROR: BULKLOAD requires BEGIN or END".to_string()),
+        },
+        ("BULKLOAD", _) => Err("ERROR: BULKLOAD requires BEGIN or END".to_string()),
+        
+        _ => Err(unknown_command_error(parts[0])),
+    }
+}
+
+// Optional trailing count for XREAD and XREADGROUP
+fn parse_stream_count(arg: Option<&&str>) -> Result<Option<usize>, String> {
+    match arg.map(|arg| arg.parse::<usize>()) {
+        None => Ok(None),
+        Some(Ok(count)) if count > 0 => Ok(Some(count)),
+        Some(_) => Err("ERROR: stream read count must be a positive integer".to_string()),
+    }
+}
+
+// Append command to WAL (write-ahead for durability)
+fn write_to_log(command: &Command) -> io::Result<()> {
+    write_batch_to_log(std::slice::from_ref(command))
+}
+
+// Open the WAL for appending. In dsync mode the handle is opened with
+// O_DSYNC, so every write returns only once its data (and the file size
+// needed to read it back) is on disk; that saves the separate fsync
+// syscall but makes each write() slower, and mtime is not synced.
+// Connections with relaxed durability never wait for the disk.
+fn open_log_for_append() -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    if WAL_SYNC_MODE.get() == Some(&WalSyncMode::Dsync) && !RELAXED_DURABILITY.get() {
+        options.custom_flags(libc::O_DSYNC);
+    }
+    options.open(WAL_PATH)
+}
+
+// Append several commands to the WAL with a single sync, giving each the
+// next write sequence number. Once durable, the records are also handed to
+// the --forward-to sink, if any. Fails once the WAL has been handed over
+// to a --reuse-port successor.
+fn write_batch_to_log(commands: &[Command]) -> io::Result<()> {
+    handoff::while_owner(|| append_batch_to_log(commands))
+}
+
+fn append_batch_to_log(commands: &[Command]) -> io::Result<()> {
+    let mut file = open_log_for_append()?;
+
+    let ts = now_millis();
+    let mut buf = Vec::new();
+    let first = {
+        let _append = LOG_APPEND.lock().unwrap();
+        let first = OP_SEQ.load(Ordering::Relaxed) + 1;
+        for (seq, command) in (first..).zip(commands) {
+            let record = LogRecord {
+                ts: Some(ts),
+                seq: Some(seq),
+                command,
+            };
+            serde_json::to_writer(&mut buf, &record)?;
+            buf.push(b'\n');
+        }
+        file.write_all(&buf)?;
+        // Only now are the numbers taken; the sync below may still fail,
+        // but the records are in the file and replay will count them
+        OP_SEQ.store(first + commands.len() as u64 - 1, Ordering::Relaxed);
+        first
+    };
+    for command in commands {
+        if let Some(key) = command.written_key() {
+            tracking::note_written(key);
+        }
+    }
+    // In dsync mode the write itself waited for the disk, and relaxed
+    // connections never wait; only an explicit fsync is timed
+    if WAL_SYNC_MODE.get() != Some(&WalSyncMode::Dsync) && !RELAXED_DURABILITY.get() {
+        let sync_started = Instant::now();
+        file.sync_all()?;
+        latency::record_fsync(sync_started.elapsed(), now_millis());
+    }
+    LOGGED_WRITE.set(Some(LoggedWrite {
+        seq: first + commands.len() as u64 - 1,
+        offset: file.stream_position()?,
+    }));
+    if let Some(forwarder) = FORWARDER.get() {
+        forwarder.forward(buf);
+    }
+
+    Ok(())
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Collect the latest mutations of a key in the WAL at path, newest first.
+// This scans the log backward from disk, so it is slow on a large log;
+// since the log is compacted at startup, history only reaches back to the
+// last restart. A log that cannot be read gives an ERROR reply.
+fn key_history(path: &str, key: &str, count: usize) -> Result<Vec<String>, String> {
+    let unreadable = |e: io::Error| format!("ERROR: cannot read history from the log: {}", e);
+    let reader = match ReverseLogReader::open(path) {
+        Ok(reader) => reader,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(unreadable(e)),
+    };
+
+    let mut entries = Vec::new();
+    for record in reader {
+        let record = record.map_err(unreadable)?;
+        if record.command.written_key() != Some(key) {
+            continue;
+        }
+        let ts = match record.ts {
+            Some(ts) => ts.to_string(),
+            None => "-".to_string(),
+        };
+        entries.push(format!("{} {}", ts, history_entry(&record.command)));
+        if entries.len() == count {
+            break;
+        }
+    }
+
+    Ok(entries)
+}
+
+// How HISTORY shows one logged mutation: the command and its arguments,
+// minus the key. Typed values show their type rather than their encoding.
+fn history_entry(command: &Command) -> String {
+    let joined = |items: &[String]| items.join(" ");
+    match command {
+        Command::SET { value, kind: None, .. } | Command::SNAPSHOT { value, kind: None, .. } => {
+            format!("SET {}", value)
+        }
+        Command::SET { kind: Some(kind), .. } | Command::SNAPSHOT { kind: Some(kind), .. } => {
+            format!("SET ({})", kind.name())
+        }
+        Command::DELETE { .. } => "DELETE".to_string(),
+        Command::PFADD { elements, .. } => format!("PFADD {}", joined(elements)).trim_end().to_string(),
+        Command::PFMERGE { sources, .. } => format!("PFMERGE {}", joined(sources)).trim_end().to_string(),
+        Command::SETBIT { offset, bit, .. } => format!("SETBIT {} {}", offset, *bit as u8),
+        Command::BITOP { op, sources, .. } => {
+            format!("BITOP {} {}", format!("{:?}", op).to_uppercase(), joined(sources))
+        }
+        Command::XADD { id, fields, .. } => {
+            let id = id.map_or("*".to_string(), |id| id.to_string());
+            let fields: Vec<String> = fields.iter().map(|(f, v)| format!("{} {}", f, v)).collect();
+            format!("XADD {} {}", id, joined(&fields))
+        }
+        Command::XGROUP { group, id, .. } => {
+            format!("XGROUP {} {}", group, id.map_or("$".to_string(), |id| id.to_string()))
+        }
+        Command::XREADGROUP { group, consumer, .. } => format!("XREADGROUP {} {}", group, consumer),
+        Command::XACK { group, ids, .. } => {
+            let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+            format!("XACK {} {}", group, joined(&ids))
+        }
+        // written_key() is None for every other command
+        _ => unreachable!(),
+    }
+}
+
+// Log a SET and apply it to a map the caller has locked; returns the old value
+fn set_logged(
+    map: &mut HashMap<String, Entry>,
+    key: String,
+    value: String,
+) -> io::Result<Option<Value>> {
+    write_to_log(&Command::SET {
+        key: key.clone(),
+        value: value.clone(),
+        kind: None,
+        get: false,
+    })?;
+    Ok(store_value(map, key, Value::from_string(value)))
+}
+
+// Apply delta to a counter under one lock, logging the resulting SET
+fn apply_incr(data: &Mutex<HashMap<String, Entry>>, key: String, delta: i64) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    let next = match map.get(&key) {
+        Some(entry) => entry.value.incr_by(delta),
+        None => Ok(delta),
+    };
+    let next = match next {
+        Ok(n) => n,
+        Err(error_msg) => return Ok(format!("{}\n", error_msg)),
+    };
+
+    set_logged(&mut map, key, next.to_string())?;
+
+    Ok(format!("{}\n", next))
+}
+
+// Amount DECRFLOOR takes from the counter at key without going below
+// zero; a missing key reads as 0
+fn decrfloor_amount(current: Option<&Value>, amount: i64) -> Result<i64, String> {
+    let available = match current {
+        Some(value) => value.incr_by(0)?,
+        None => 0,
+    };
+    Ok(amount.min(available.max(0)))
+}
+
+// Decrement by up to amount under one lock, clamping at zero; replies
+// with the amount actually deducted and logs the result as a SET
+fn apply_decrfloor(
+    data: &Mutex<HashMap<String, Entry>>,
+    key: String,
+    amount: i64,
+) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    let current = map.get(&key).map(|e| &e.value);
+    let deducted = match decrfloor_amount(current, amount) {
+        Ok(deducted) => deducted,
+        Err(error_msg) => return Ok(format!("{}\n", error_msg)),
+    };
+    if let (Some(Value::Int(n)), true) = (current, deducted > 0) {
+        let next = n - deducted;
+        set_logged(&mut map, key, next.to_string())?;
+    }
+
+    Ok(format!("{}\n", deducted))
+}
+
+// Counter value after adding delta, or None if it would leave
+// [min, max]; a missing key reads as 0
+fn clampincr_value(current: Option<&Value>, delta: i64, min: i64, max: i64) -> Result<Option<i64>, String> {
+    let next = current.map_or(Ok(delta), |value| value.incr_by(delta))?;
+    Ok((min..=max).contains(&next).then_some(next))
+}
+
+// Add delta under one lock only if the result stays within [min, max].
+// Replies 0 when rejected, or 1 and the new value on two lines, logging
+// the result as a SET.
+fn apply_clampincr(
+    data: &Mutex<HashMap<String, Entry>>,
+    key: String,
+    delta: i64,
+    min: i64,
+    max: i64,
+) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    match clampincr_value(map.get(&key).map(|e| &e.value), delta, min, max) {
+        Ok(Some(next)) => {
+            set_logged(&mut map, key, next.to_string())?;
+            Ok(format!("1\n{}\n", next))
+        }
+        Ok(None) => Ok("0\n".to_string()),
+        Err(error_msg) => Ok(format!("{}\n", error_msg)),
+    }
+}
+
+// Next RATELIMIT value for key as "<window start secs>:<count>". The
+// count restarts at 1 once now falls in a later fixed window; there is no
+// expiry, so an idle counter stays until its next call resets it.
+fn ratelimit_value(current: Option<&Value>, window: u64, now_secs: u64) -> Result<(u64, u64), String> {
+    let start = now_secs - now_secs % window;
+    let count = match current.map(|value| value.to_string()) {
+        Some(value) => {
+            let (stored_start, count) = value
+                .split_once(':')
+                .and_then(|(s, c)| Some((s.parse::<u64>().ok()?, c.parse::<u64>().ok()?)))
+                .ok_or_else(|| "ERROR: value is not a rate limit counter".to_string())?;
+            if stored_start == start { count + 1 } else { 1 }
+        }
+        None => 1,
+    };
+    Ok((start, count))
+}
+
+// Count a hit in key's current window under one lock. Replies with the
+// count on the first line and 1 if it exceeds limit (else 0) on the second.
+fn apply_ratelimit(
+    data: &Mutex<HashMap<String, Entry>>,
+    key: String,
+    limit: u64,
+    window: u64,
+) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    let now_secs = now_millis() / 1000;
+    let (start, count) = match ratelimit_value(map.get(&key).map(|e| &e.value), window, now_secs) {
+        Ok(next) => next,
+        Err(error_msg) => return Ok(format!("{}\n", error_msg)),
+    };
+    set_logged(&mut map, key, format!("{}:{}", start, count))?;
+
+    Ok(format!("{}\n{}\n", count, (count > limit) as u8))
+}
+
+// Set key only if its version still matches; version 0 means absent
+fn apply_setver(
+    data: &Mutex<HashMap<String, Entry>>,
+    key: String,
+    value: String,
+    expected: u64,
+) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    let current = map.get(&key).map_or(0, |entry| entry.version);
+    if current != expected {
+        return Ok(format!("ERROR: version mismatch, current version is {}\n", current));
+    }
+
+    set_logged(&mut map, key, value)?;
+
+    Ok(format!("{}\n", current + 1))
+}
+
+// Add elements to the sketch at key; Some(new sketch) if anything changed.
+// Shared by PFADD and WAL replay so both produce the same registers.
+fn pfadd_value(current: Option<&Value>, elements: &[String]) -> Result<Option<Hll>, String> {
+    let mut hll = match current {
+        Some(Value::Hll(hll)) => hll.clone(),
+        Some(_) => return Err(WRONGTYPE_HLL.to_string()),
+        None => Hll::new(),
+    };
+
+    let mut changed = current.is_none();
+    for element in elements {
+        changed |= hll.add(element.as_bytes());
+    }
+
+    Ok(if changed { Some(hll) } else { None })
+}
+
+// PFADD is logged as itself and only when the sketch changed
+fn apply_pfadd(
+    data: &Mutex<HashMap<String, Entry>>,
+    key: String,
+    elements: Vec<String>,
+) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    let hll = match pfadd_value(map.get(&key).map(|e| &e.value), &elements) {
+        Ok(Some(hll)) => hll,
+        Ok(None) => return Ok("0\n".to_string()),
+        Err(error_msg) => return Ok(format!("{}\n", error_msg)),
+    };
+
+    write_to_log(&Command::PFADD {
+        key: key.clone(),
+        elements,
+    })?;
+    store_value(&mut map, key, Value::Hll(hll));
+
+    Ok("1\n".to_string())
+}
+
+// Estimate the cardinality of the union of the sketches at keys
+fn pfcount(data: &Mutex<HashMap<String, Entry>>, keys: &[String]) -> Result<u64, String> {
+    let map = LOCK_STATS.lock(data);
+
+    let mut union = Hll::new();
+    for key in keys {
+        match map.get(key).map(|e| &e.value) {
+            Some(Value::Hll(hll)) => union.merge(hll),
+            Some(_) => return Err(WRONGTYPE_HLL.to_string()),
+            None => {}
+        }
+    }
+
+    Ok(union.count())
+}
+
+// Union of dest (if present) and every source sketch. Shared by PFMERGE
+// and WAL replay; missing sources count as empty sketches.
+fn pfmerge_value(
+    map: &HashMap<String, Entry>,
+    dest: &str,
+    sources: &[String],
+) -> Result<Hll, String> {
+    let mut merged = Hll::new();
+    for key in std::iter::once(dest).chain(sources.iter().map(|s| s.as_str())) {
+        match map.get(key).map(|e| &e.value) {
+            Some(Value::Hll(hll)) => merged.merge(hll),
+            Some(_) => return Err(WRONGTYPE_HLL.to_string()),
+            None => {}
+        }
+    }
+    Ok(merged)
+}
+
+// PFMERGE is logged as itself; replay recomputes the union in order
+fn apply_pfmerge(
+    data: &Mutex<HashMap<String, Entry>>,
+    dest: String,
+    sources: Vec<String>,
+) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    let merged = match pfmerge_value(&map, &dest, &sources) {
+        Ok(merged) => merged,
+        Err(error_msg) => return Ok(format!("{}\n", error_msg)),
+    };
+
+    write_to_log(&Command::PFMERGE {
+        dest: dest.clone(),
+        sources,
+    })?;
+    store_value(&mut map, dest, Value::Hll(merged));
+
+    Ok("OK\n".to_string())
+}
+
+// Set a bit in the bitmap at key, creating it if absent; returns the old
+// bit. Shared by SETBIT and WAL replay.
+fn setbit_in_map(
+    map: &mut HashMap<String, Entry>,
+    key: String,
+    offset: u64,
+    bit: bool,
+) -> Result<u8, String> {
+    match map.get_mut(&key) {
+        Some(Entry { value: Value::Bitmap(bytes), version }) => {
+            *version += 1;
+            Ok(bitmap::set_bit(bytes, offset, bit))
+        }
+        Some(_) => Err(WRONGTYPE.to_string()),
+        None => {
+            let mut bytes = Vec::new();
+            let old = bitmap::set_bit(&mut bytes, offset, bit);
+            map.insert(key, Entry { value: Value::Bitmap(bytes), version: 1 });
+            KEY_CREATED.notify_all();
+            Ok(old)
+        }
+    }
+}
+
+// SETBIT is logged as itself, after the type check so replay never fails
+fn apply_setbit(
+    data: &Mutex<HashMap<String, Entry>>,
+    key: String,
+    offset: u64,
+    bit: bool,
+) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    if let Some(entry) = map.get(&key)
+        && !matches!(entry.value, Value::Bitmap(_))
+    {
+        return Ok(format!("{}\n", WRONGTYPE));
+    }
+
+    write_to_log(&Command::SETBIT {
+        key: key.clone(),
+        offset,
+        bit,
+    })?;
+    let response = match setbit_in_map(&mut map, key, offset, bit) {
+        Ok(old) => format!("{}\n", old),
+        Err(error_msg) => format!("{}\n", error_msg),
+    };
+
+    Ok(response)
+}
+
+// Compute a BITOP result; missing sources read as empty bitmaps.
+// Shared by BITOP and WAL replay.
+fn bitop_value(
+    map: &HashMap<String, Entry>,
+    op: BitOp,
+    sources: &[String],
+) -> Result<Vec<u8>, String> {
+    let mut inputs: Vec<&[u8]> = Vec::with_capacity(sources.len());
+    for key in sources {
+        match map.get(key).map(|e| &e.value) {
+            Some(Value::Bitmap(bytes)) => inputs.push(bytes),
+            Some(_) => return Err(WRONGTYPE.to_string()),
+            None => inputs.push(&[]),
+        }
+    }
+    Ok(bitmap::bit_op(op, &inputs))
+}
+
+// An empty result deletes dest, as in Redis
+fn store_bitop_result(map: &mut HashMap<String, Entry>, dest: String, result: Vec<u8>) {
+    if result.is_empty() {
+        map.remove(&dest);
+    } else {
+        store_value(map, dest, Value::Bitmap(result));
+    }
+}
+
+// BITOP is logged as itself; replies with the result length in bytes
+fn apply_bitop(
+    data: &Mutex<HashMap<String, Entry>>,
+    op: BitOp,
+    dest: String,
+    sources: Vec<String>,
+) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    let result = match bitop_value(&map, op, &sources) {
+        Ok(result) => result,
+        Err(error_msg) => return Ok(format!("{}\n", error_msg)),
+    };
+
+    write_to_log(&Command::BITOP {
+        op,
+        dest: dest.clone(),
+        sources,
+    })?;
+    let len = result.len();
+    store_bitop_result(&mut map, dest, result);
+
+    Ok(format!("{}\n", len))
+}
+
+// Run a read against the bitmap at key; a missing key reads as empty
+fn read_bitmap<T>(
+    data: &Mutex<HashMap<String, Entry>>,
+    key: &str,
+    read: impl FnOnce(&[u8]) -> T,
+) -> Result<T, String> {
+    let map = LOCK_STATS.lock(data);
+    match map.get(key).map(|e| &e.value) {
+        Some(Value::Bitmap(bytes)) => Ok(read(bytes)),
+        Some(_) => Err(WRONGTYPE.to_string()),
+        None => Ok(read(&[])),
+    }
+}
+
+// Bytes start..=end of the value at key, as in Redis: negative offsets
+// count from the end, out-of-range offsets are clamped, and an empty or
+// inverted range (or a missing key) gives an empty string. A slice that
+// splits a multi-byte character has it replaced with U+FFFD. Typed values
+// are WRONGTYPE rather than slices of their encoding.
+fn getrange(
+    data: &Mutex<HashMap<String, Entry>>,
+    key: &str,
+    start: i64,
+    end: i64,
+) -> Result<String, String> {
+    let map = LOCK_STATS.lock(data);
+    let value = match map.get(key).map(|e| &e.value) {
+        Some(Value::Hll(_) | Value::Bitmap(_) | Value::Stream(_)) => return Err(WRONGTYPE.to_string()),
+        Some(value) => value.to_string(),
+        None => return Ok(String::new()),
+    };
+    drop(map);
+
+    let len = value.len() as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let end = if end < 0 { len + end } else { end.min(len - 1) };
+    if start > end || start >= len {
+        return Ok(String::new());
+    }
+
+    let bytes = &value.as_bytes()[start as usize..=end as usize];
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+// Overwrite the value at key from byte offset, zero-padding past the end;
+// a missing key reads as empty. None when there is nothing to write.
+fn setrange_value(current: Option<&Value>, offset: usize, value: &str) -> Result<Option<String>, String> {
+    let mut bytes = match current {
+        Some(Value::Hll(_) | Value::Bitmap(_) | Value::Stream(_)) => return Err(WRONGTYPE.to_string()),
+        Some(current) => current.to_string().into_bytes(),
+        None => Vec::new(),
+    };
+    if value.is_empty() {
+        return Ok(None);
+    }
+
+    let end = offset + value.len();
+    if bytes.len() < end {
+        bytes.resize(end, 0);
+    }
+    bytes[offset..end].copy_from_slice(value.as_bytes());
+
+    String::from_utf8(bytes)
+        .map(Some)
+        .map_err(|_| "ERROR: SETRANGE would split a multi-byte character".to_string())
+}
+
+// SETRANGE is logged as a SET of the whole new value; replies with its
+// length in bytes
+fn apply_setrange(
+    data: &Mutex<HashMap<String, Entry>>,
+    key: String,
+    offset: usize,
+    value: String,
+) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    let current = map.get(&key).map(|e| &e.value);
+    let new = match setrange_value(current, offset, &value) {
+        Ok(Some(new)) => new,
+        Ok(None) => {
+            let len = current.map_or(0, |value| value.to_string().len());
+            return Ok(format!("{}\n", len));
+        }
+        Err(error_msg) => return Ok(format!("{}\n", error_msg)),
+    };
+    let len = new.len();
+    set_logged(&mut map, key, new)?;
+
+    Ok(format!("{}\n", len))
+}
+
+// Parse a stored value as a JSON document
+fn stored_json(value: &Value) -> Result<serde_json::Value, String> {
+    match value {
+        Value::Hll(_) | Value::Bitmap(_) | Value::Stream(_) => Err(WRONGTYPE.to_string()),
+        _ => serde_json::from_str(&value.to_string())
+            .map_err(|_| "ERROR: stored value is not valid JSON".to_string()),
+    }
+}
+
+// Apply a JSONSET to the document at key, returning the new document text
+fn jsonset_value(current: Option<&Value>, path: &str, value: &str) -> Result<String, String> {
+    let new: serde_json::Value = serde_json::from_str(value)
+        .map_err(|_| "ERROR: value is not valid JSON".to_string())?;
+    let segments = json_path::parse_path(path);
+
+    let mut doc = match current {
+        Some(current) => stored_json(current)?,
+        None if segments.is_empty() => serde_json::Value::Null,
+        None => return Err("ERROR: new JSON documents must be created at the root path".to_string()),
+    };
+    json_path::set(&mut doc, &segments, new)?;
+
+    Ok(doc.to_string())
+}
+
+// JSONSET rewrites the whole document and is logged as a SET of it
+fn apply_jsonset(
+    data: &Mutex<HashMap<String, Entry>>,
+    key: String,
+    path: String,
+    value: String,
+) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    let doc = match jsonset_value(map.get(&key).map(|e| &e.value), &path, &value) {
+        Ok(doc) => doc,
+        Err(error_msg) => return Ok(format!("{}\n", error_msg)),
+    };
+    set_logged(&mut map, key, doc)?;
+
+    Ok("OK\n".to_string())
+}
+
+// Serialized JSON at path, or None if the key or path is missing
+fn jsonget(
+    data: &Mutex<HashMap<String, Entry>>,
+    key: &str,
+    path: &str,
+) -> Result<Option<String>, String> {
+    let map = LOCK_STATS.lock(data);
+
+    let doc = match map.get(key) {
+        Some(entry) => stored_json(&entry.value)?,
+        None => return Ok(None),
+    };
+    let segments = json_path::parse_path(path);
+
+    Ok(json_path::get(&doc, &segments).map(|node| node.to_string()))
+}
+
+// Move key to the ring value after its current one, starting from the
+// first value when the key is absent or holds a string not in the ring.
+// Typed values are WRONGTYPE rather than replaced.
+fn apply_cycle(
+    data: &Mutex<HashMap<String, Entry>>,
+    key: String,
+    ring: Vec<String>,
+) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    let current = match map.get(&key).map(|entry| &entry.value) {
+        Some(Value::Hll(_) | Value::Bitmap(_) | Value::Stream(_)) => return Ok(format!("{}\n", WRONGTYPE)),
+        current => current.map(|value| value.to_string()),
+    };
+    let next = current
+        .and_then(|current| ring.iter().position(|value| *value == current))
+        .map_or(0, |i| (i + 1) % ring.len());
+    let value = ring[next].clone();
+    set_logged(&mut map, key, value.clone())?;
+
+    Ok(format!("{}\n", value))
+}
+
+// Set key only while the whole store is empty; 1 if this call claimed it
+fn apply_initif(
+    data: &Mutex<HashMap<String, Entry>>,
+    key: String,
+    value: String,
+) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    if !map.is_empty() {
+        return Ok("0\n".to_string());
+    }
+    set_logged(&mut map, key, value)?;
+
+    Ok("1\n".to_string())
+}
+
+// Value at key, or default after setting key to it when absent. The read
+// and the logged SET share one lock, so racing callers all get the value
+// the first of them set.
+fn apply_getorset(
+    data: &Mutex<HashMap<String, Entry>>,
+    key: String,
+    default: String,
+) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    if let Some(entry) = map.get(&key) {
+        let reply = string_value(&entry.value).unwrap_or_else(|error_msg| error_msg);
+        return Ok(format!("{}\n", reply));
+    }
+    let response = format!("{}\n", default);
+    set_logged(&mut map, key, default)?;
+
+    Ok(response)
+}
+
+// ID an XADD would assign in the stream at key: the next auto ID for '*',
+// otherwise the given ID, which must be past the last entry
+fn xadd_id(current: Option<&Value>, id: Option<StreamId>) -> Result<StreamId, String> {
+    let empty = Stream::default();
+    let stream = match current {
+        Some(Value::Stream(stream)) => stream,
+        Some(_) => return Err(WRONGTYPE.to_string()),
+        None => &empty,
+    };
+
+    match id {
+        None => stream.next_id(now_millis()).ok_or_else(|| {
+            "ERROR: The stream has exhausted the last possible ID, unable to add more items".to_string()
+        }),
+        Some(id) if id > stream.last_id() => Ok(id),
+        Some(_) => Err("ERROR: The ID specified in XADD is equal or smaller than the target stream top item".to_string()),
+    }
+}
+
+// Append an entry to the stream at key, creating it if absent. Shared by
+// XADD and WAL replay; the ID has already been checked by xadd_id.
+fn xadd_in_map(
+    map: &mut HashMap<String, Entry>,
+    key: String,
+    id: StreamId,
+    fields: Vec<(String, String)>,
+) -> Result<(), String> {
+    match map.get_mut(&key) {
+        Some(Entry { value: Value::Stream(stream), version }) => {
+            *version += 1;
+            stream.append(id, fields);
+            Ok(())
+        }
+        Some(_) => Err(WRONGTYPE.to_string()),
+        None => {
+            let mut stream = Stream::default();
+            stream.append(id, fields);
+            map.insert(key, Entry { value: Value::Stream(stream), version: 1 });
+            KEY_CREATED.notify_all();
+            Ok(())
+        }
+    }
+}
+
+// XADD is logged as itself with the assigned ID, so replay never depends
+// on the clock; replies with that ID
+fn apply_xadd(
+    data: &Mutex<HashMap<String, Entry>>,
+    key: String,
+    id: Option<StreamId>,
+    fields: Vec<(String, String)>,
+) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    let id = match xadd_id(map.get(&key).map(|e| &e.value), id) {
+        Ok(id) => id,
+        Err(error_msg) => return Ok(format!("{}\n", error_msg)),
+    };
+
+    write_to_log(&Command::XADD {
+        key: key.clone(),
+        id: Some(id),
+        fields: fields.clone(),
+    })?;
+    let response = match xadd_in_map(&mut map, key, id, fields) {
+        Ok(()) => format!("{}\n", id),
+        Err(error_msg) => format!("{}\n", error_msg),
+    };
+
+    Ok(response)
+}
+
+// Run a read against the stream at key; a missing key reads as empty
+fn read_stream<T>(
+    data: &Mutex<HashMap<String, Entry>>,
+    key: &str,
+    read: impl FnOnce(&Stream) -> T,
+) -> Result<T, String> {
+    let map = LOCK_STATS.lock(data);
+    match map.get(key).map(|e| &e.value) {
+        Some(Value::Stream(stream)) => Ok(read(stream)),
+        Some(_) => Err(WRONGTYPE.to_string()),
+        None => Ok(read(&Stream::default())),
+    }
+}
+
+// Stream at key for mutation, bumping the key's version; None if the key
+// is absent or not a stream
+fn stream_mut<'a>(map: &'a mut HashMap<String, Entry>, key: &str) -> Option<&'a mut Stream> {
+    match map.get_mut(key) {
+        Some(Entry { value: Value::Stream(stream), version }) => {
+            *version += 1;
+            Some(stream)
+        }
+        _ => None,
+    }
+}
+
+// Stream entries as a count line followed by one line per entry: the ID
+// and then its field value pairs
+fn entry_lines<'a>(entries: impl IntoIterator<Item = &'a StreamEntry>) -> String {
+    let mut count = 0;
+    let mut lines = String::new();
+    for entry in entries {
+        lines.push_str(&entry.id.to_string());
+        for (field, value) in &entry.fields {
+            lines.push_str(&format!(" {} {}", field, value));
+        }
+        lines.push('\n');
+        count += 1;
+    }
+    format!("{}\n{}", count, lines)
+}
+
+// ID an XGROUP CREATE starts the group at: the given ID, or the stream's
+// last ID for '$'. The stream must exist and not have the group yet.
+fn xgroup_id(current: Option<&Value>, group: &str, id: Option<StreamId>) -> Result<StreamId, String> {
+    match current {
+        Some(Value::Stream(stream)) if stream.has_group(group) => {
+            Err("ERROR: BUSYGROUP Consumer Group name already exists".to_string())
+        }
+        Some(Value::Stream(stream)) => Ok(id.unwrap_or_else(|| stream.last_id())),
+        Some(_) => Err(WRONGTYPE.to_string()),
+        None => Err("ERROR: XGROUP CREATE requires the key to exist".to_string()),
+    }
+}
+
+// XGROUP CREATE is logged as itself with the resolved starting ID
+fn apply_xgroup(
+    data: &Mutex<HashMap<String, Entry>>,
+    key: String,
+    group: String,
+    id: Option<StreamId>,
+) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    let id = match xgroup_id(map.get(&key).map(|e| &e.value), &group, id) {
+        Ok(id) => id,
+        Err(error_msg) => return Ok(format!("{}\n", error_msg)),
+    };
+
+    write_to_log(&Command::XGROUP {
+        key: key.clone(),
+        group: group.clone(),
+        id: Some(id),
+    })?;
+    if let Some(stream) = stream_mut(&mut map, &key) {
+        stream.create_group(group, id);
+    }
+
+    Ok("OK\n".to_string())
+}
+
+// The stream at key, provided it has the group
+fn group_stream<'a>(current: Option<&'a Value>, key: &str, group: &str) -> Result<&'a Stream, String> {
+    match current {
+        Some(Value::Stream(stream)) if stream.has_group(group) => Ok(stream),
+        Some(Value::Stream(_)) | None => Err(format!(
+            "ERROR: NOGROUP No such key '{}' or consumer group '{}'",
+            key, group
+        )),
+        Some(_) => Err(WRONGTYPE.to_string()),
+    }
+}
+
+// '>' delivers the group's next entries to consumer and is logged, since
+// it moves the group forward; replay redelivers the same entries because
+// the log preserves stream order. Any other ID re-reads the consumer's
+// pending entries after it.
+fn apply_xreadgroup(
+    data: &Mutex<HashMap<String, Entry>>,
+    group: String,
+    consumer: String,
+    key: String,
+    id: Option<StreamId>,
+    count: Option<usize>,
+) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    let stream = match group_stream(map.get(&key).map(|e| &e.value), &key, &group) {
+        Ok(stream) => stream,
+        Err(error_msg) => return Ok(format!("{}\n", error_msg)),
+    };
+    if let Some(id) = id {
+        let pending = stream.pending_for(&group, &consumer, id);
+        let count = count.unwrap_or(pending.len());
+        return Ok(entry_lines(pending.into_iter().take(count)));
+    }
+    if stream.undelivered(&group, count).is_empty() {
+        return Ok("0\n".to_string());
+    }
+
+    write_to_log(&Command::XREADGROUP {
+        group: group.clone(),
+        consumer: consumer.clone(),
+        key: key.clone(),
+        id: None,
+        count,
+    })?;
+    let delivered = match stream_mut(&mut map, &key) {
+        Some(stream) => stream.deliver(&group, &consumer, count),
+        None => Vec::new(),
+    };
+
+    Ok(entry_lines(&delivered))
+}
+
+// XACK is logged as itself, and only when it acknowledges something
+fn apply_xack(
+    data: &Mutex<HashMap<String, Entry>>,
+    key: String,
+    group: String,
+    ids: Vec<StreamId>,
+) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    match map.get(&key).map(|e| &e.value) {
+        Some(Value::Stream(stream)) if ids.iter().any(|id| stream.is_pending(&group, *id)) => {}
+        Some(Value::Stream(_)) | None => return Ok("0\n".to_string()),
+        Some(_) => return Ok(format!("{}\n", WRONGTYPE)),
+    }
+
+    write_to_log(&Command::XACK {
+        key: key.clone(),
+        group: group.clone(),
+        ids: ids.clone(),
+    })?;
+    let acked = stream_mut(&mut map, &key).map_or(0, |stream| stream.ack(&group, &ids));
+
+    Ok(format!("{}\n", acked))
+}
+
+// Set every pair only if cond_key currently holds expected, logging the
+// SETs as one batch; 1 if they were applied. Only a string cond_key can
+// match; a typed one is WRONGTYPE.
+fn apply_setif(
+    data: &Mutex<HashMap<String, Entry>>,
+    cond_key: String,
+    expected: String,
+    pairs: Vec<(String, String)>,
+) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    let current = match map.get(&cond_key).map(|entry| &entry.value) {
+        Some(Value::Hll(_) | Value::Bitmap(_) | Value::Stream(_)) => return Ok(format!("{}\n", WRONGTYPE)),
+        current => current.map(|value| value.to_string()),
+    };
+    if current.as_deref() != Some(expected.as_str()) {
+        return Ok("0\n".to_string());
+    }
+
+    let sets: Vec<Command> = pairs
+        .iter()
+        .map(|(key, value)| Command::SET {
+            key: key.clone(),
+            value: value.clone(),
+            kind: None,
+            get: false,
+        })
+        .collect();
+    write_batch_to_log(&sets)?;
+    for (key, value) in pairs {
+        store_value(&mut map, key, Value::from_string(value));
+    }
+
+    Ok("1\n".to_string())
+}
+
+// Delete key only if it currently holds expected, logging the DELETE;
+// 1 if it was deleted. Only string values compare, so a typed value's
+// encoding can never match.
+fn apply_cadel(data: &Mutex<HashMap<String, Entry>>, key: String, expected: String) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    let current = match map.get(&key).map(|entry| &entry.value) {
+        Some(Value::Hll(_) | Value::Bitmap(_) | Value::Stream(_)) => return Ok(format!("{}\n", WRONGTYPE)),
+        current => current.map(|value| value.to_string()),
+    };
+    if current.as_deref() != Some(expected.as_str()) {
+        return Ok("0\n".to_string());
+    }
+
+    write_to_log(&Command::DELETE { key: key.clone() })?;
+    map.remove(&key);
+
+    Ok("1\n".to_string())
+}
+
+// Longest common subsequence of the string values at two keys, or its
+// length. Both values are read under one lock and compared after it is
+// released; missing keys read as empty strings.
+fn lcs(
+    data: &Mutex<HashMap<String, Entry>>,
+    key1: &str,
+    key2: &str,
+    len_only: bool,
+) -> Result<String, String> {
+    let map = LOCK_STATS.lock(data);
+    let read = |key: &str| match map.get(key).map(|e| &e.value) {
+        Some(Value::Hll(_) | Value::Bitmap(_) | Value::Stream(_)) => Err(WRONGTYPE.to_string()),
+        Some(value) => Ok(value.to_string().chars().collect::<Vec<char>>()),
+        None => Ok(Vec::new()),
+    };
+    let (a, b) = (read(key1)?, read(key2)?);
+    drop(map);
+
+    if a.len().saturating_mul(b.len()) > MAX_LCS_CELLS {
+        return Err(format!(
+            "ERROR: LCS values too large, length product exceeds {}",
+            MAX_LCS_CELLS
+        ));
+    }
+
+    Ok(if len_only {
+        lcs::lcs_len(&a, &b).to_string()
+    } else {
+        lcs::lcs(&a, &b)
+    })
+}
+
+// Round-trip a canary key through the WAL and the map: log and store it,
+// read it back, then log its deletion and check it is gone. The lock is
+// held throughout, so no other client can observe the canary.
+fn selftest(data: &Mutex<HashMap<String, Entry>>) -> Result<(), String> {
+    let mut map = LOCK_STATS.lock(data);
+    if map.contains_key(SELFTEST_KEY) {
+        return Err(format!("setup: key '{}' is in use", SELFTEST_KEY));
+    }
+
+    let canary = now_millis().to_string();
+    set_logged(&mut map, SELFTEST_KEY.to_string(), canary.clone())
+        .map_err(|e| format!("WAL write: {}", e))?;
+
+    match map.get(SELFTEST_KEY) {
+        Some(entry) if entry.value.to_string() == canary => {}
+        _ => return Err("read back: canary value not found".to_string()),
+    }
+
+    write_to_log(&Command::DELETE {
+        key: SELFTEST_KEY.to_string(),
+    })
+    .map_err(|e| format!("WAL delete: {}", e))?;
+    map.remove(SELFTEST_KEY);
+
+    if map.contains_key(SELFTEST_KEY) {
+        return Err("delete: canary still present".to_string());
+    }
+    Ok(())
+}
+
+// Exchange the values of two keys under one lock. A key whose partner is
+// absent is deleted; both keys' new states are logged as one batch.
+fn apply_swapkeys(
+    data: &Mutex<HashMap<String, Entry>>,
+    key1: String,
+    key2: String,
+) -> io::Result<String> {
+    let mut map = LOCK_STATS.lock(data);
+
+    let value1 = map.get(&key1).map(|e| e.value.clone());
+    let value2 = map.get(&key2).map(|e| e.value.clone());
+    if key1 == key2 || (value1.is_none() && value2.is_none()) {
+        return Ok("OK\n".to_string());
+    }
+
+    let swapped = [(key1, value2), (key2, value1)];
+    let records: Vec<Command> = swapped
+        .iter()
+        .map(|(key, value)| match value {
+            Some(value) => Command::SET {
+                key: key.clone(),
+                value: value.to_string(),
+                kind: value.kind(),
+                get: false,
+            },
+            None => Command::DELETE { key: key.clone() },
+        })
+        .collect();
+    write_batch_to_log(&records)?;
+
+    for (key, value) in swapped {
+        match value {
+            Some(value) => {
+                store_value(&mut map, key, value);
+            }
+            None => {
+                map.remove(&key);
+            }
+        }
+    }
+
+    Ok("OK\n".to_string())
+}
+
+// Server statistics as INFO's field:value lines
+fn info_lines() -> Vec<String> {
+    let (avg, p99) = LOCK_STATS.summary();
+    vec![
+        format!("lock_acquisitions:{}", LOCK_STATS.acquisitions()),
+        format!("lock_wait_samples:{}", LOCK_STATS.samples_taken()),
+        format!("lock_wait_avg_us:{}", avg),
+        format!("lock_wait_p99_us:{}", p99),
+        format!("last_op_seq:{}", OP_SEQ.load(Ordering::Relaxed)),
+        format!("ready:{}", !LAME_DUCK.load(Ordering::Relaxed) as u8),
+        format!("idempotency_tokens:{}", idempotency::len()),
+        format!("forward_dropped_batches:{}", forward_dropped()),
+    ]
+}
+
+// Batches --forward-to has dropped; 0 when not forwarding
+fn forward_dropped() -> u64 {
+    FORWARDER.get().map_or(0, Forwarder::dropped)
+}
+
+// The key count and the same rough per-key memory estimate replay uses,
+// summed over all keys under the data lock, so it is O(keys) and bounded
+// by the command time budget.
+fn memory_estimate(data: &Mutex<HashMap<String, Entry>>) -> Result<(usize, u64), String> {
+    let mut budget = budget::Budget::start();
+    let map = LOCK_STATS.lock(data);
+    let mut memory = 0;
+    for key in map.keys() {
+        budget.check()?;
+        memory += entry_size(&map, key);
+    }
+    Ok((map.len(), memory))
+}
+
+// Every counter and gauge as one JSON object for METRICS, given the
+// result of memory_estimate.
+fn metrics(keys: usize, memory: u64) -> io::Result<serde_json::Value> {
+    let wal_bytes = match std::fs::metadata(WAL_PATH) {
+        Ok(meta) => meta.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+
+    let mut commands = serde_json::Map::new();
+    let mut total = 0;
+    for (name, count) in COMMAND_NAMES.iter().zip(&COMMAND_COUNTS) {
+        let count = count.load(Ordering::Relaxed);
+        total += count;
+        if count > 0 {
+            commands.insert(name.to_string(), count.into());
+        }
+    }
+
+    let (avg, p99) = LOCK_STATS.summary();
+    let fsync = latency::fsync_summary();
+    let (mmap_live_bytes, mmap_segments) = mmap_values::usage();
+    Ok(serde_json::json!({
+        "commands_processed": total,
+        "commands": commands,
+        "connections": CONNECTIONS.load(Ordering::Relaxed),
+        "keys": keys,
+        "memory_bytes_estimate": memory,
+        "wal_bytes": wal_bytes,
+        "last_op_seq": OP_SEQ.load(Ordering::Relaxed),
+        "lock_acquisitions": LOCK_STATS.acquisitions(),
+        "lock_wait_samples": LOCK_STATS.samples_taken(),
+        "lock_wait_avg_us": avg,
+        "lock_wait_p99_us": p99,
+        "wal_fsync_samples": fsync.samples,
+        "wal_fsync_p50_us": fsync.p50,
+        "wal_fsync_p99_us": fsync.p99,
+        "wal_fsync_max_us": fsync.max,
+        "idempotency_tokens": idempotency::len(),
+        "mmap_live_bytes": mmap_live_bytes,
+        "mmap_segments": mmap_segments,
+        "forward_dropped_batches": forward_dropped(),
+        "ready": !LAME_DUCK.load(Ordering::Relaxed),
+    }))
+}
+
+// Count a parsed command under the name it was sent with
+fn count_command(line: &str) {
+    let name = line.split_whitespace().next().unwrap_or("");
+    if let Some(index) = COMMAND_NAMES.iter().position(|n| n.eq_ignore_ascii_case(name)) {
+        COMMAND_COUNTS[index].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Value of key, waiting up to timeout for a write to create it. Waits
+// in slices of at most a second so a shutdown ends them too.
+fn bget(
+    data: &Mutex<HashMap<String, Entry>>,
+    key: &str,
+    timeout: Option<Duration>,
+    shutdown: &AtomicBool,
+) -> Result<Option<String>, String> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut map = LOCK_STATS.lock(data);
+    loop {
+        if let Some(entry) = map.get(key) {
+            return string_value(&entry.value).map(Some);
+        }
+        let remaining = match deadline {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+            None => Duration::MAX,
+        };
+        if remaining.is_zero() || shutdown.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        map = KEY_CREATED.wait_timeout(map, remaining.min(Duration::from_secs(1))).unwrap().0;
+    }
+}
+
+// Reduce the integer values of keys under one lock, so the result reflects
+// a single point in time. Missing keys are skipped; a key holding anything
+// but an integer fails the whole command. SUM of no values is 0, other
+// operations reply (nil); AVG may be fractional.
+fn aggregate(data: &Mutex<HashMap<String, Entry>>, op: AggregateOp, keys: &[String]) -> Result<String, String> {
+    let mut budget = budget::Budget::start();
+    let map = LOCK_STATS.lock(data);
+    let mut values = Vec::with_capacity(keys.len());
+    for key in keys {
+        budget.check()?;
+        match map.get(key).map(|e| &e.value) {
+            Some(Value::Int(n)) => values.push(*n),
+            Some(_) => return Err(format!("ERROR: value of '{}' is not an integer", key)),
+            None => {}
+        }
+    }
+    drop(map);
+
+    let sum: i128 = values.iter().map(|&n| n as i128).sum();
+    let result = match op {
+        AggregateOp::Sum => match i64::try_from(sum) {
+            Ok(sum) => Some(sum.to_string()),
+            Err(_) => return Err("ERROR: AGGREGATE SUM would overflow".to_string()),
+        },
+        AggregateOp::Min => values.iter().min().map(|n| n.to_string()),
+        AggregateOp::Max => values.iter().max().map(|n| n.to_string()),
+        AggregateOp::Avg => (!values.is_empty()).then(|| (sum as f64 / values.len() as f64).to_string()),
+    };
+    Ok(result.unwrap_or_else(|| "(nil)".to_string()))
+}
+
+// The count keys with the largest entry_size estimate, biggest first, as
+// (key, size, type). One pass over every key under the data lock, so it
+// is O(keys) and holds the lock for the whole scan, up to the command
+// time budget.
+fn bigkeys(data: &Mutex<HashMap<String, Entry>>, count: usize) -> Result<Vec<(String, u64, &'static str)>, String> {
+    let mut budget = budget::Budget::start();
+    let map = LOCK_STATS.lock(data);
+    // Min-heap of the biggest seen so far, so the smallest is dropped first
+    let mut biggest = BinaryHeap::with_capacity(count + 1);
+    for key in map.keys() {
+        budget.check()?;
+        biggest.push(Reverse((entry_size(&map, key), key)));
+        if biggest.len() > count {
+            biggest.pop();
+        }
+    }
+    Ok(biggest
+        .into_sorted_vec()
+        .into_iter()
+        .map(|Reverse((size, key))| (key.clone(), size, map[key].value.type_name()))
+        .collect())
+}
+
+// Key a SCANVALUE cursor resumes after: None for "0", which starts a
+// scan, otherwise the key the cursor was made from
+fn scanvalue_cursor(cursor: &str) -> Option<Option<String>> {
+    if cursor == "0" {
+        return Some(None);
+    }
+    let key = String::from_utf8(Codec::Base64.decode(cursor)?).ok()?;
+    Some(Some(key))
+}
+
+// Examine the next SCANVALUE_BATCH keys in sorted order after the cursor
+// key, returning the next cursor ("0" once done) and the keys whose string
+// or integer value matches pattern. The cursor is the last key examined,
+// base64-encoded, so it does not depend on the map's layout: a key that
+// exists for the whole scan is examined exactly once, while keys added or
+// removed mid-scan may or may not be. Values are matched when examined.
+// Picking the batch visits every key, so each call is O(keys) under the
+// data lock and bounded by the command time budget.
+fn scanvalue(
+    data: &Mutex<HashMap<String, Entry>>,
+    after: Option<&str>,
+    pattern: &str,
+) -> Result<(String, Vec<String>), String> {
+    let mut budget = budget::Budget::start();
+    let map = LOCK_STATS.lock(data);
+    // Max-heap of the smallest keys past the cursor, so the largest is
+    // dropped first
+    let mut batch = BinaryHeap::with_capacity(SCANVALUE_BATCH + 1);
+    let mut remaining = 0;
+    for key in map.keys() {
+        budget.check()?;
+        if after.is_some_and(|after| key.as_str() <= after) {
+            continue;
+        }
+        remaining += 1;
+        batch.push(key);
+        if batch.len() > SCANVALUE_BATCH {
+            batch.pop();
+        }
+    }
+
+    let batch = batch.into_sorted_vec();
+    let mut keys = Vec::new();
+    for key in &batch {
+        let matched = match &map[*key].value {
+            Value::Str(s) => glob::glob_match(pattern, s),
+            Value::Mapped(s) => glob::glob_match(pattern, s),
+            Value::Int(n) => glob::glob_match(pattern, &n.to_string()),
+            _ => false,
+        };
+        if matched {
+            keys.push(key.to_string());
+        }
+    }
+    let next = match batch.last() {
+        Some(last) if remaining > SCANVALUE_BATCH => Codec::Base64.encode(last),
+        _ => "0".to_string(),
+    };
+    Ok((next, keys))
+}
+
+// Start lame-duck mode: keep serving but report not ready, then begin the
+// normal shutdown once the grace period ends. Under --reuse-port the WAL
+// is handed over too, once every acknowledged bulk SET has been logged,
+// so only reads are served from then on. False if already started.
+fn enter_lame_duck(shutdown: &Arc<AtomicBool>, grace: Duration) -> bool {
+    if LAME_DUCK.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+
+    println!("Entering lame-duck mode for {}s", grace.as_secs());
+    let shutdown = Arc::clone(shutdown);
+    std::thread::spawn(move || {
+        // Connections flush their loads within a read timeout of seeing
+        // LAME_DUCK, and log any later bulk SET before acknowledging it
+        while UNLOGGED_BULK_SETS.load(Ordering::SeqCst) > 0 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        handoff::release();
+        std::thread::sleep(grace);
+        shutdown.store(true, Ordering::Relaxed);
+    });
+    true
+}
+
+// Apply a connection's buffered bulk SETs under one lock and one fsync
+fn flush_bulk(data: &Mutex<HashMap<String, Entry>>, bulk: &mut BulkLoad) -> io::Result<()> {
+    if bulk.pending.is_empty() {
+        return Ok(());
+    }
+
+    let mut map = LOCK_STATS.lock(data);
+    write_batch_to_log(&bulk.pending)?;
+    UNLOGGED_BULK_SETS.fetch_sub(bulk.pending.len() as u64, Ordering::SeqCst);
+    for command in bulk.pending.drain(..) {
+        if let Command::SET { key, value, .. } = command {
+            store_value(&mut map, key, Value::from_string(value));
+            bulk.loaded += 1;
+        }
+    }
+
+    Ok(())
+}
+
+// Check a write against current state without applying or logging it
+fn validate_write(data: &Mutex<HashMap<String, Entry>>, command: &Command) -> Result<(), String> {
+    let map = LOCK_STATS.lock(data);
+    match command {
+        Command::INCR { key } => map.get(key).map_or(Ok(0), |e| e.value.incr_by(1)).map(|_| ()),
+        Command::DECR { key } => map.get(key).map_or(Ok(0), |e| e.value.incr_by(-1)).map(|_| ()),
+        Command::SETRANGE { key, offset, value } => {
+            setrange_value(map.get(key).map(|e| &e.value), *offset, value).map(|_| ())
+        }
+        Command::RATELIMIT { key, window, .. } => {
+            ratelimit_value(map.get(key).map(|e| &e.value), *window, now_millis() / 1000).map(|_| ())
+        }
+        Command::DECRFLOOR { key, amount } => {
+            decrfloor_amount(map.get(key).map(|e| &e.value), *amount).map(|_| ())
+        }
+        Command::CLAMPINCR { key, delta, min, max } => {
+            clampincr_value(map.get(key).map(|e| &e.value), *delta, *min, *max).map(|_| ())
+        }
+        Command::PFADD { key, elements } => {
+            pfadd_value(map.get(key).map(|e| &e.value), elements).map(|_| ())
+        }
+        Command::PFMERGE { dest, sources } => pfmerge_value(&map, dest, sources).map(|_| ()),
+        Command::SETBIT { key, .. } => match map.get(key).map(|e| &e.value) {
+            Some(Value::Bitmap(_)) | None => Ok(()),
+            Some(_) => Err(WRONGTYPE.to_string()),
+        },
+        Command::BITOP { op, sources, .. } => bitop_value(&map, *op, sources).map(|_| ()),
+        Command::JSONSET { key, path, value } => {
+            jsonset_value(map.get(key).map(|e| &e.value), path, value).map(|_| ())
+        }
+        Command::XADD { key, id, .. } => xadd_id(map.get(key).map(|e| &e.value), *id).map(|_| ()),
+        Command::XGROUP { key, group, id } => {
+            xgroup_id(map.get(key).map(|e| &e.value), group, *id).map(|_| ())
+        }
+        Command::XREADGROUP { group, key, .. } => {
+            group_stream(map.get(key).map(|e| &e.value), key, group).map(|_| ())
+        }
+        Command::XACK { key, .. } => match map.get(key).map(|e| &e.value) {
+            Some(Value::Stream(_)) | None => Ok(()),
+            Some(_) => Err(WRONGTYPE.to_string()),
+        },
+        Command::SETVER { key, expected, .. } => {
+            let current = map.get(key).map_or(0, |entry| entry.version);
+            if current == *expected {
+                Ok(())
+            } else {
+                Err(format!("ERROR: version mismatch, current version is {}", current))
+            }
+        }
+        Command::SELFTEST if map.contains_key(SELFTEST_KEY) => {
+            Err(format!("ERROR: SELFTEST failed at setup: key '{}' is in use", SELFTEST_KEY))
+        }
+        Command::SHUTDOWN => match LAME_DUCK_GRACE.get().copied().flatten() {
+            Some(_) if LAME_DUCK.load(Ordering::Relaxed) => {
+                Err("ERROR: already in lame-duck mode".to_string())
+            }
+            Some(_) => Ok(()),
+            None => Err("ERROR: lame-duck mode requires --lame-duck-seconds".to_string()),
+        },
+        _ => Ok(()),
+    }
+}
+
+// With OPSEQ on, append the sequence number of the write just logged to the
+// reply's last line. Values never contain whitespace, so the token is
+// unambiguous; writes that log nothing get no token.
+fn tag_with_seq(mut response: String, op_seq: bool) -> String {
+    if let Some(write) = LOGGED_WRITE.get()
+        && op_seq
+    {
+        response.pop();
+        response.push_str(&format!(" seq:{}\n", write.seq));
+    }
+    response
+}
+
+// Cap a count-prefixed multi-line reply at max bytes, keeping whole
+// entry lines. A truncated reply has its count rewritten to the lines
+// kept and ends with a TRUNCATED line; with_cursor adds the first word of
+// the last kept line (a stream ID) to resume from. Other replies, such as
+// errors, pass through unchanged.
+fn cap_reply(response: String, max: Option<usize>, with_cursor: bool) -> String {
+    let Some(max) = max else {
+        return response;
+    };
+    let Some((count, body)) = response.split_once('\n') else {
+        return response;
+    };
+    if count.parse::<usize>().is_err() || response.len() <= max {
+        return response;
+    }
+
+    let mut kept = 0;
+    let mut size = count.len() + 1;
+    let mut cursor = None;
+    for line in body.lines() {
+        let next = size + line.len() + 1;
+        if next > max {
+            break;
+        }
+        size = next;
+        kept += 1;
+        cursor = line.split(' ').next();
+    }
+
+    let mut capped = format!("{}\n", kept);
+    for line in body.lines().take(kept) {
+        capped.push_str(line);
+        capped.push('\n');
+    }
+    match cursor {
+        Some(cursor) if with_cursor => capped.push_str(&format!("TRUNCATED {}\n", cursor)),
+        _ => capped.push_str("TRUNCATED\n"),
+    }
+    capped
+}
+
+// SCANVALUE reply: the next cursor, a count line, then one key per line.
+// Past max bytes, trailing keys are dropped and a TRUNCATED line ends the
+// reply. The cursor then rewinds to the last key kept, or to the cursor
+// given if none fit, so calling again with it returns the dropped keys.
+fn scanvalue_reply(given: &str, next: String, keys: &[String], max: Option<usize>) -> String {
+    let reply = |cursor: &str, keys: &[String]| {
+        let mut response = format!("{}\n{}\n", cursor, keys.len());
+        for key in keys {
+            response.push_str(key);
+            response.push('\n');
+        }
+        response
+    };
+    let response = reply(&next, keys);
+    let Some(max) = max else {
+        return response;
+    };
+    // The cursor and count lines are never cut
+    if response.len() <= max || keys.is_empty() {
+        return response;
+    }
+
+    for kept in (0..keys.len()).rev() {
+        let cursor = match kept {
+            0 => given.to_string(),
+            _ => Codec::Base64.encode(&keys[kept - 1]),
+        };
+        let capped = reply(&cursor, &keys[..kept]);
+        if capped.len() <= max || kept == 0 {
+            return capped + "TRUNCATED\n";
+        }
+    }
+    unreachable!("a reply over max has at least one key")
+}
+
+// GET ... DECODE reply line. The decoded bytes must be text without
+// whitespace, since anything else would break the line protocol.
+fn decoded_reply(value: &str, codec: Codec) -> String {
+    let Some(bytes) = codec.decode(value) else {
+        return "ERROR: value is not valid for the requested encoding\n".to_string();
+    };
+    match String::from_utf8(bytes) {
+        Ok(text) if !text.contains(char::is_whitespace) => format!("{}\n", text),
+        _ => "ERROR: decoded value cannot be sent as a single word of text\n".to_string(),
+    }
+}
+
+// VERBOSE trailer sent after a reply: execution time, whether the command
+// wrote to the WAL, and the WAL offset it left behind ('-' if none)
+fn verbose_trailer(elapsed: Duration) -> String {
+    match LOGGED_WRITE.get() {
+        Some(write) => format!(
+            "META exec_us:{} wal:1 wal_offset:{}\n",
+            elapsed.as_micros(),
+            write.offset
+        ),
+        None => format!("META exec_us:{} wal:0 wal_offset:-\n", elapsed.as_micros()),
+    }
+}
+
+// Handle client connection in dedicated thread
+fn handle_client(
+    stream: TcpStream, 
+    addr: SocketAddr, 
+    shutdown: Arc<AtomicBool>, 
+    data: Arc<Mutex<HashMap<String, Entry>>>
+) -> io::Result<()> {
+    println!("new client: {addr:?}");
+
+    // Timeout allows checking shutdown flag periodically
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    let mut stream_clone = Recorder::new(stream.try_clone()?);
+    let mut reader = BufReader::new(stream);
+
+    // In dry-run mode writes, SELFTEST and SHUTDOWN are validated but never
+    // applied; reads still return live data
+    let mut dry_run = false;
+
+    // Whether write replies carry their sequence number
+    let mut op_seq = false;
+
+    // Whether each reply is followed by a META trailer line
+    let mut verbose = false;
+
+    // Byte limit on list replies (HISTORY, INFO, CONFIG DUMP, BIGKEYS,
+    // LATENCY HISTORY, SCANVALUE and stream reads); see README.md for
+    // the replies it leaves whole
+    let mut max_reply: Option<usize> = None;
+
+    // Between BULKLOAD BEGIN and END only plain SETs are accepted; they are
+    // acknowledged immediately and made durable a batch at a time, or one
+    // at a time once lame-duck mode starts
+    let mut bulk: Option<BulkLoad> = None;
+
+    // Registered while CLIENT TRACKING is on
+    let mut tracker: Option<Tracker> = None;
+
+    let connected_at = Instant::now();
+    let max_age = MAX_CONNECTION_AGE.get().copied().flatten();
+
+    loop {
+        if shutdown.load(Ordering::Relaxed) {
+            println!("Worker thread shutting down gracefully");
+            break;
+        }
+
+        // Log acknowledged bulk SETs before the WAL can be handed over
+        if let Some(load) = bulk.as_mut()
+            && LAME_DUCK.load(Ordering::SeqCst)
+        {
+            flush_bulk(&data, load)?;
+        }
+
+        // Checked between commands, so a reply is never cut short
+        if max_age.is_some_and(|age| connected_at.elapsed() >= age) {
+            stream_clone.write_all(b"-RECONNECT connection max age reached\n")?;
+            stream_clone.flush()?;
+            break;
+        }
+
+        // Sent between replies, at most a read timeout after the write
+        if let Some(tracker) = &tracker {
+            let invalidated = tracker.take_invalidations();
+            for key in &invalidated {
+                stream_clone.write_all(format!(">INVALIDATE {}\n", key).as_bytes())?;
+            }
+            if !invalidated.is_empty() {
+                stream_clone.flush()?;
+            }
+        }
+    
+        let mut buffer = String::new();
+    
+        match reader.read_line(&mut buffer) {
+            Ok(0) => break, // Client disconnected
+            Ok(_bytes_read) => {
+                let started = Instant::now();
+                LOGGED_WRITE.set(None);
+
+                // A repeated token answers with the saved reply instead
+                // of running the command; dry runs never use the cache
+                let (parsed, token) = parse_with_token(&buffer);
+                if parsed.is_ok() {
+                    count_command(&buffer);
+                }
+                // Track before reading, so a write that lands after the
+                // read is always reported
+                if let (Some(tracker), Ok(command)) = (&tracker, &parsed) {
+                    let keys = command.read_keys();
+                    if !keys.is_empty() {
+                        tracker.track(keys);
+                    }
+                }
+                let mut pending = None;
+                let mut saved_reply = None;
+                if let Some((token, request)) = token
+                    && !dry_run
+                {
+                    match idempotency::claim(&token, &request) {
+                        Claim::New(claim) => {
+                            stream_clone.start_recording();
+                            pending = Some(claim);
+                        }
+                        Claim::Replay(reply) => saved_reply = Some(reply),
+                        Claim::Busy => {
+                            saved_reply = Some(b"ERROR: a request with this idempotency token is still in progress\n".to_vec());
+                        }
+                        Claim::Mismatch => {
+                            saved_reply = Some(b"ERROR: idempotency token was already used for a different command\n".to_vec());
+                        }
+                    }
+                }
+
+                // BGET waits by design, so its time is not a spike
+                let blocking = matches!(parsed, Ok(Command::BGET { .. }));
+                // A WAL handed over after the READONLY check still fails the
+                // write; that is a reply, not a reason to drop the connection
+                let outcome = (|| -> io::Result<()> {
+                    match parsed {
+                        _ if saved_reply.is_some() => {
+                            stream_clone.write_all(&saved_reply.take().unwrap())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(command) if command.changes_state() && handoff::released() => {
+                            stream_clone.write_all(format!("{}\n", READONLY).as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(command) if dry_run && command.changes_state() => {
+                            let response = match validate_write(&data, &command) {
+                                Ok(()) => "DRYRUN OK\n".to_string(),
+                                Err(error_msg) => format!("{}\n", error_msg),
+                            };
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::BULKLOAD { phase: BulkPhase::Begin }) => {
+                            let response = if bulk.is_some() {
+                                "ERROR: BULKLOAD already in progress\n"
+                            } else {
+                                bulk = Some(BulkLoad {
+                                    data: Arc::clone(&data),
+                                    pending: Vec::new(),
+                                    loaded: 0,
+                                });
+                                "OK\n"
+                            };
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::BULKLOAD { phase: BulkPhase::End }) => {
+                            let response = match bulk.take() {
+                                Some(mut load) => {
+                                    flush_bulk(&data, &mut load)?;
+                                    format!("{}\n", load.loaded)
+                                }
+                                None => "ERROR: no BULKLOAD in progress\n".to_string(),
+                            };
+                            let response = tag_with_seq(response, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(command @ Command::SET { get: false, .. }) if bulk.is_some() => {
+                            let load = bulk.as_mut().unwrap();
+                            // Counted before LAME_DUCK is checked, so either the
+                            // handoff waits for this SET or it is logged here
+                            UNLOGGED_BULK_SETS.fetch_add(1, Ordering::SeqCst);
+                            load.pending.push(command);
+                            if load.pending.len() >= BULKLOAD_BATCH_SIZE || LAME_DUCK.load(Ordering::SeqCst) {
+                                flush_bulk(&data, load)?;
+                            }
+                            // No seq tag: it is only known once the batch is
+                            // logged, and BULKLOAD END reports the last one
+                            stream_clone.write_all(b"OK\n")?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(_) if bulk.is_some() => {
+                            stream_clone.write_all(b"ERROR: only SET is allowed during BULKLOAD\n")?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::DRYRUN { enabled }) => {
+                            dry_run = enabled;
+                            stream_clone.write_all(b"OK\n")?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::OPSEQ { enabled }) => {
+                            op_seq = enabled;
+                            stream_clone.write_all(b"OK\n")?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::VERBOSE { enabled }) => {
+                            verbose = enabled;
+                            stream_clone.write_all(b"OK\n")?;
+                            stream_clone.flush()?;
+                        }
+
+                        // Relaxed writes reach disk with the next fsync of the
+                        // WAL (any strict write in fsync mode) or the kernel's
+                        // own writeback
+                        Ok(Command::DURABILITY { relaxed }) => {
+                            RELAXED_DURABILITY.set(relaxed);
+                            stream_clone.write_all(b"OK\n")?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::CLIENT { subcommand: ClientSubcommand::Tracking { enabled } }) => {
+                            if !enabled {
+                                tracker = None;
+                            } else if tracker.is_none() {
+                                tracker = Some(Tracker::register());
+                            }
+                            stream_clone.write_all(b"OK\n")?;
+                            stream_clone.flush()?;
+                        }
+
+                        // Count line first, then one spike per line, newest
+                        // first, as its end time in millis, cause and duration
+                        // in microseconds
+                        Ok(Command::LATENCY { subcommand: LatencySubcommand::History }) => {
+                            let events = latency::history();
+                            let mut response = format!("{}\n", events.len());
+                            for event in events {
+                                response.push_str(&format!("{} {} {}\n", event.ts, event.cause, event.micros));
+                            }
+                            let response = cap_reply(response, max_reply, false);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::MAXREPLY { bytes }) => {
+                            max_reply = (bytes > 0).then_some(bytes);
+                            stream_clone.write_all(b"OK\n")?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::SET { key, value, get, .. }) => {
+                            // Log under the lock so the returned old value
+                            // matches the order writes land in the WAL
+                            let mut map = LOCK_STATS.lock(&data);
+                            let old = map.get(&key).map(|entry| string_value(&entry.value));
+                            let response = match (get, old) {
+                                // The old value could not be replied with
+                                (true, Some(Err(error_msg))) => format!("{}\n", error_msg),
+                                (get, old) => {
+                                    set_logged(&mut map, key, value)?;
+                                    match (get, old) {
+                                        (true, Some(Ok(old))) => format!("{}\n", old),
+                                        (true, _) => "(nil)\n".to_string(),
+                                        (false, _) => "OK\n".to_string(),
+                                    }
+                                }
+                            };
+                            drop(map);
+
+                            let response = tag_with_seq(response, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+            
+                        // Old value (or nil) on the first line, new value on the second
+                        Ok(Command::SWAP { key, value }) => {
+                            let mut map = LOCK_STATS.lock(&data);
+                            let response = match map.get(&key).map(|entry| string_value(&entry.value)) {
+                                Some(Err(error_msg)) => format!("{}\n", error_msg),
+                                Some(Ok(old)) => {
+                                    set_logged(&mut map, key, value.clone())?;
+                                    format!("{}\n{}\n", old, value)
+                                }
+                                None => {
+                                    set_logged(&mut map, key, value.clone())?;
+                                    format!("(nil)\n{}\n", value)
+                                }
+                            };
+                            drop(map);
+
+                            let response = tag_with_seq(response, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::SWAPKEYS { key1, key2 }) => {
+                            let response = tag_with_seq(apply_swapkeys(&data, key1, key2)?, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::PFADD { key, elements }) => {
+                            let response = tag_with_seq(apply_pfadd(&data, key, elements)?, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::PFCOUNT { keys }) => {
+                            let response = match pfcount(&data, &keys) {
+                                Ok(count) => format!("{}\n", count),
+                                Err(error_msg) => format!("{}\n", error_msg),
+                            };
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::PFMERGE { dest, sources }) => {
+                            let response = tag_with_seq(apply_pfmerge(&data, dest, sources)?, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::SETBIT { key, offset, bit }) => {
+                            let response = tag_with_seq(apply_setbit(&data, key, offset, bit)?, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::GETBIT { key, offset }) => {
+                            let response = match read_bitmap(&data, &key, |b| bitmap::get_bit(b, offset)) {
+                                Ok(bit) => format!("{}\n", bit),
+                                Err(error_msg) => format!("{}\n", error_msg),
+                            };
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::BITCOUNT { key, range }) => {
+                            let (start, end) = range.unwrap_or((0, -1));
+                            let response = match read_bitmap(&data, &key, |b| bitmap::bit_count(b, start, end)) {
+                                Ok(count) => format!("{}\n", count),
+                                Err(error_msg) => format!("{}\n", error_msg),
+                            };
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::BITOP { op, dest, sources }) => {
+                            let response = tag_with_seq(apply_bitop(&data, op, dest, sources)?, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::JSONSET { key, path, value }) => {
+                            let response = tag_with_seq(apply_jsonset(&data, key, path, value)?, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::JSONGET { key, path }) => {
+                            let response = match jsonget(&data, &key, &path) {
+                                Ok(Some(json)) => format!("{}\n", json),
+                                Ok(None) => "(nil)\n".to_string(),
+                                Err(error_msg) => format!("{}\n", error_msg),
+                            };
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::LCS { key1, key2, len_only }) => {
+                            let response = match lcs(&data, &key1, &key2, len_only) {
+                                Ok(result) => format!("{}\n", result),
+                                Err(error_msg) => format!("{}\n", error_msg),
+                            };
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::CYCLE { key, ring }) => {
+                            let response = tag_with_seq(apply_cycle(&data, key, ring)?, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::XADD { key, id, fields }) => {
+                            let response = tag_with_seq(apply_xadd(&data, key, id, fields)?, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        // Count line first, then one entry per line as the ID
+                        // followed by its field value pairs
+                        Ok(Command::XRANGE { key, start, end }) => {
+                            let response = match read_stream(&data, &key, |s| entry_lines(s.range(start, end))) {
+                                Ok(lines) => cap_reply(lines, max_reply, true),
+                                Err(error_msg) => format!("{}\n", error_msg),
+                            };
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::XREAD { key, after, count }) => {
+                            let response = match read_stream(&data, &key, |s| entry_lines(s.after(after, count))) {
+                                Ok(lines) => cap_reply(lines, max_reply, true),
+                                Err(error_msg) => format!("{}\n", error_msg),
+                            };
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::XGROUP { key, group, id }) => {
+                            let response = tag_with_seq(apply_xgroup(&data, key, group, id)?, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::XREADGROUP { group, consumer, key, id, count }) => {
+                            // Entries cut by MAXREPLY stay pending and can be
+                            // re-read from the cursor
+                            let response = apply_xreadgroup(&data, group, consumer, key, id, count)?;
+                            let response = tag_with_seq(cap_reply(response, max_reply, true), op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::XACK { key, group, ids }) => {
+                            let response = tag_with_seq(apply_xack(&data, key, group, ids)?, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::XLEN { key }) => {
+                            let response = match read_stream(&data, &key, |stream| stream.len()) {
+                                Ok(len) => format!("{}\n", len),
+                                Err(error_msg) => format!("{}\n", error_msg),
+                            };
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::SETIF { cond_key, expected, pairs }) => {
+                            let response = apply_setif(&data, cond_key, expected, pairs)?;
+                            let response = tag_with_seq(response, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::CADEL { key, expected }) => {
+                            let response = tag_with_seq(apply_cadel(&data, key, expected)?, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::INITIF { key, value }) => {
+                            let response = tag_with_seq(apply_initif(&data, key, value)?, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::GETORSET { key, default }) => {
+                            let response = tag_with_seq(apply_getorset(&data, key, default)?, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::AGGREGATE { op, keys }) => {
+                            let response = match aggregate(&data, op, &keys) {
+                                Ok(result) => format!("{}\n", result),
+                                Err(error_msg) => format!("{}\n", error_msg),
+                            };
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        // Next cursor on the first line, then a count line and
+                        // one matching key per line
+                        Ok(Command::SCANVALUE { cursor, pattern }) => {
+                            let response = match scanvalue(&data, cursor.as_deref(), &pattern) {
+                                Ok((next, keys)) => {
+                                    let given = cursor.map_or("0".to_string(), |key| Codec::Base64.encode(&key));
+                                    scanvalue_reply(&given, next, &keys, max_reply)
+                                }
+                                Err(error_msg) => format!("{}\n", error_msg),
+                            };
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        // 1 only if both keys exist with equal values; both are
+                        // read under one lock so a concurrent write can't split them
+                        Ok(Command::CMPEQ { key1, key2 }) => {
+                            let map = LOCK_STATS.lock(&data);
+                            let equal = match (map.get(&key1), map.get(&key2)) {
+                                (Some(a), Some(b)) => a.value == b.value,
+                                _ => false,
+                            };
+                            drop(map);
+                            let response = format!("{}\n", equal as u8);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        // Count line first, then one field:value per line
+                        Ok(Command::INFO) => {
+                            let lines = info_lines();
+                            let mut response = format!("{}\n", lines.len());
+                            for line in lines {
+                                response.push_str(&line);
+                                response.push('\n');
+                            }
+                            let response = cap_reply(response, max_reply, false);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        // Count line first, then one setting per line as its
+                        // flag name, value, and 'default' or 'flag'
+                        Ok(Command::CONFIG { subcommand: ConfigSubcommand::Dump }) => {
+                            let settings = CONFIG.get().map(Config::dump).unwrap_or_default();
+                            let mut response = format!("{}\n", settings.len());
+                            for (name, value, overridden) in settings {
+                                let source = if overridden { "flag" } else { "default" };
+                                response.push_str(&format!("{} {} {}\n", name, value, source));
+                            }
+                            let response = cap_reply(response, max_reply, false);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
 
-// Append command to WAL (write-ahead for durability)
-fn write_to_log(command: &Command) -> io::Result<()> {
-    let mut file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open("kvstore.log")?;
+                        // One line of JSON
+                        Ok(Command::METRICS) => {
+                            let response = match memory_estimate(&data) {
+                                Ok((keys, memory)) => format!("{}\n", metrics(keys, memory)?),
+                                Err(error_msg) => format!("{}\n", error_msg),
+                            };
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
 
-    let json = serde_json::to_string(command)?;
-    file.write_all(json.as_bytes())?;
-    file.write_all(b"\n")?;
-    file.sync_all()?;
+                        // Unix seconds on the first line, microseconds within
+                        // that second on the second, as in Redis
+                        Ok(Command::TIME) => {
+                            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                            let response = format!("{}\n{}\n", now.as_secs(), now.subsec_micros());
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
 
-    Ok(())
-}
+                        Ok(Command::SELFTEST) => {
+                            let response = match selftest(&data) {
+                                Ok(()) => "OK\n".to_string(),
+                                Err(stage) => format!("ERROR: SELFTEST failed at {}\n", stage),
+                            };
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
 
-// Handle client connection in dedicated thread
-fn handle_client(
-    stream: TcpStream, 
-    addr: SocketAddr, 
-    shutdown: Arc<AtomicBool>, 
-    data: Arc<Mutex<HashMap<String, String>>>
-) -> io::Result<()> {
-    println!("new client: {addr:?}");
+                        Ok(Command::SHUTDOWN) => {
+                            let response = match LAME_DUCK_GRACE.get().copied().flatten() {
+                                Some(grace) if enter_lame_duck(&shutdown, grace) => "OK\n",
+                                Some(_) => "ERROR: already in lame-duck mode\n",
+                                None => "ERROR: lame-duck mode requires --lame-duck-seconds\n",
+                            };
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
 
-    let mut stream_clone = stream.try_clone()?;
-    let mut reader = BufReader::new(stream);
+                        Ok(Command::GETRANGE { key, start, end }) => {
+                            let response = match getrange(&data, &key, start, end) {
+                                Ok(range) => format!("{}\n", range),
+                                Err(error_msg) => format!("{}\n", error_msg),
+                            };
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
 
-    // Timeout allows checking shutdown flag periodically
-    stream_clone.set_read_timeout(Some(Duration::from_secs(1)))?;
+                        Ok(Command::SETRANGE { key, offset, value }) => {
+                            let response = tag_with_seq(apply_setrange(&data, key, offset, value)?, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
 
-    loop {
-        if shutdown.load(Ordering::Relaxed) {
-            println!("Worker thread shutting down gracefully");
-            break;
-        }
-    
-        let mut buffer = String::new();
-    
-        match reader.read_line(&mut buffer) {
-            Ok(0) => break, // Client disconnected
-            Ok(_bytes_read) => {
-                match parse_command(&buffer) {
-                    Ok(Command::SET { key, value }) => {
-                        write_to_log(&Command::SET { 
-                            key: key.clone(), 
-                            value: value.clone() 
-                        })?;
-
-                        let mut map = data.lock().unwrap();
-                        map.insert(key, value);
-                        drop(map);
-                        
-                        stream_clone.write_all(b"OK\n")?;
-                        stream_clone.flush()?;
-                    }
+                        Ok(Command::BGET { key, timeout }) => {
+                            let response = match bget(&data, &key, timeout, &shutdown) {
+                                Ok(Some(value)) => format!("{}\n", value),
+                                Ok(None) => "(nil)\n".to_string(),
+                                Err(error_msg) => format!("{}\n", error_msg),
+                            };
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::GET { key, decode }) => {
+                            let map = LOCK_STATS.lock(&data);
+                            let response = match map.get(&key).map(|entry| string_value(&entry.value)) {
+                                Some(Ok(value)) if decode != Codec::None => decoded_reply(&value, decode),
+                                Some(Ok(value)) => format!("{}\n", value),
+                                Some(Err(error_msg)) => format!("{}\n", error_msg),
+                                None => "(nil)\n".to_string(),
+                            };
+                            drop(map);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
             
-                    Ok(Command::GET { key }) => {
-                        let map = data.lock().unwrap();
-                        let response = match map.get(&key) {
-                            Some(value) => format!("{}\n", value),
-                            None => "(nil)\n".to_string(),
-                        };
-                        drop(map);
-                        stream_clone.write_all(response.as_bytes())?;
-                        stream_clone.flush()?;
-                    }
+                        // Log under the lock so WAL order matches the order
+                        // the map changes in
+                        Ok(Command::DELETE { key }) => {
+                            let mut map = LOCK_STATS.lock(&data);
+                            write_to_log(&Command::DELETE {
+                                key: key.clone(),
+                            })?;
+                            let response = match map.remove(&key) {
+                                Some(_) => "OK\n",
+                                None => "(nil)\n",
+                            };
+                            drop(map);
+                            let response = tag_with_seq(response.to_string(), op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        // DELETE that replies OK whether or not the key existed
+                        Ok(Command::UNSET { key }) => {
+                            let mut map = LOCK_STATS.lock(&data);
+                            write_to_log(&Command::DELETE {
+                                key: key.clone(),
+                            })?;
+                            map.remove(&key);
+                            drop(map);
+                            let response = tag_with_seq("OK\n".to_string(), op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::INCR { key }) => {
+                            let response = tag_with_seq(apply_incr(&data, key, 1)?, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::DECR { key }) => {
+                            let response = tag_with_seq(apply_incr(&data, key, -1)?, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::RATELIMIT { key, limit, window }) => {
+                            let response = tag_with_seq(apply_ratelimit(&data, key, limit, window)?, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::CLAMPINCR { key, delta, min, max }) => {
+                            let response = tag_with_seq(apply_clampincr(&data, key, delta, min, max)?, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::DECRFLOOR { key, amount }) => {
+                            let response = tag_with_seq(apply_decrfloor(&data, key, amount)?, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        // Value on the first line, version on the second
+                        Ok(Command::GETVER { key }) => {
+                            let map = LOCK_STATS.lock(&data);
+                            let response = match map.get(&key) {
+                                Some(entry) => match string_value(&entry.value) {
+                                    Ok(value) => format!("{}\n{}\n", value, entry.version),
+                                    Err(error_msg) => format!("{}\n", error_msg),
+                                },
+                                None => "(nil)\n".to_string(),
+                            };
+                            drop(map);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::SETVER { key, value, expected }) => {
+                            let response = tag_with_seq(apply_setver(&data, key, value, expected)?, op_seq);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        Ok(Command::OBJECT { subcommand, key }) => {
+                            let map = LOCK_STATS.lock(&data);
+                            let response = match (map.get(&key), subcommand) {
+                                (Some(entry), ObjectSubcommand::Encoding) => {
+                                    format!("{}\n", entry.value.encoding())
+                                }
+                                (Some(entry), ObjectSubcommand::Refcount) => {
+                                    format!("{}\n", entry.value.refcount())
+                                }
+                                (None, _) => "(nil)\n".to_string(),
+                            };
+                            drop(map);
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        // Count line first, then one key per line with its
+                        // estimated size in bytes and its type
+                        Ok(Command::BIGKEYS { count }) => {
+                            let response = match bigkeys(&data, count) {
+                                Ok(keys) => {
+                                    let mut response = format!("{}\n", keys.len());
+                                    for (key, size, type_name) in keys {
+                                        response.push_str(&format!("{} {} {}\n", key, size, type_name));
+                                    }
+                                    cap_reply(response, max_reply, false)
+                                }
+                                Err(error_msg) => format!("{}\n", error_msg),
+                            };
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        // Count line first, then one mutation per line
+                        Ok(Command::HISTORY { key, count }) => {
+                            let max = MAX_HISTORY_ENTRIES.get().copied().unwrap_or(DEFAULT_MAX_HISTORY_ENTRIES);
+                            let response = match key_history(WAL_PATH, &key, count.min(max)) {
+                                Ok(entries) => {
+                                    let mut response = format!("{}\n", entries.len());
+                                    for entry in entries {
+                                        response.push_str(&entry);
+                                        response.push('\n');
+                                    }
+                                    cap_reply(response, max_reply, false)
+                                }
+                                Err(error_msg) => format!("{}\n", error_msg),
+                            };
+                            stream_clone.write_all(response.as_bytes())?;
+                            stream_clone.flush()?;
+                        }
+
+                        // Compaction-only records; parse_command never produces them
+                        Ok(Command::SNAPSHOT { .. } | Command::LASTSEQ { .. }) => unreachable!(),
             
-                    Ok(Command::DELETE { key }) => {
-                        write_to_log(&Command::DELETE { 
-                            key: key.clone(), 
-                        })?;
-
-                        let mut map = data.lock().unwrap();
-                        let response = match map.remove(&key) {
-                            Some(_) => "OK\n",
-                            None => "(nil)\n",
-                        };
-                        drop(map);
-                        stream_clone.write_all(response.as_bytes())?;
-                        stream_clone.flush()?;
+                        Err(error_msg) => {
+                            stream_clone.write_all(error_msg.as_bytes())?;
+                            stream_clone.write_all(b"\n")?;
+                            stream_clone.flush()?;
+                        }
                     }
-            
-                    Err(error_msg) => {
-                        stream_clone.write_all(error_msg.as_bytes())?;
-                        stream_clone.write_all(b"\n")?;
+                    Ok(())
+                })();
+                match outcome {
+                    Err(e) if handoff::is_handed_over(&e) => {
+                        stream_clone.write_all(format!("{}\n", READONLY).as_bytes())?;
                         stream_clone.flush()?;
                     }
+                    outcome => outcome?,
+                }
+
+                if let Some(claim) = pending {
+                    claim.complete(stream_clone.take_recording());
+                }
+                tracking::publish_written();
+
+                if !blocking {
+                    latency::record(started.elapsed(), now_millis(), || {
+                        let name = buffer.split_whitespace().next().unwrap_or("");
+                        format!("command:{}", name.to_uppercase())
+                    });
+                }
+
+                if verbose {
+                    stream_clone.write_all(verbose_trailer(started.elapsed()).as_bytes())?;
+                    stream_clone.flush()?;
                 }
             }
             Err(e) if e.kind() == io::ErrorKind::WouldBlock 
@@ -203,21 +3827,76 @@ fn handle_client(
         }
     }
 
+    // SETs already acknowledged during an unfinished bulk load still land
+    drop(bulk);
+
     println!("Client disconnected");
     Ok(())
 }
 
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:6379")
-        .expect("Failed to bind");
+    let config = Config::from_args(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+
+    if let Err(e) = install_command_renames(&config.rename_commands) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+    CONFIG.set(config.clone()).unwrap();
+    WAL_SYNC_MODE.set(config.wal_sync_mode).unwrap();
+    let lame_duck_grace = config.lame_duck_seconds.map(Duration::from_secs);
+    LAME_DUCK_GRACE.set(lame_duck_grace).unwrap();
+    MAX_CONNECTION_AGE.set(config.max_connection_age.map(Duration::from_secs)).unwrap();
+    MAX_HISTORY_ENTRIES.set(config.history_max.unwrap_or(DEFAULT_MAX_HISTORY_ENTRIES)).unwrap();
+    if config.intern_values {
+        intern::enable();
+    }
+    if config.mmap_values
+        && let Err(e) = mmap_values::enable()
+    {
+        eprintln!("Failed to create mapped value file: {e}");
+        std::process::exit(1);
+    }
+    let latency_threshold = config.latency_threshold_ms.unwrap_or(DEFAULT_LATENCY_THRESHOLD_MS);
+    latency::set_threshold(Duration::from_millis(latency_threshold));
+    if let Some(millis) = config.command_timeout_ms {
+        budget::set_timeout(Duration::from_millis(millis));
+    }
+    let idempotency_window = config.idempotency_window.unwrap_or(DEFAULT_IDEMPOTENCY_WINDOW_SECS);
+    idempotency::init(Duration::from_secs(idempotency_window));
+    if let Some(addr) = config.forward_to {
+        println!("Forwarding writes to {addr}");
+        let _ = FORWARDER.set(Forwarder::start(addr));
+    }
+
+    let addr: SocketAddr = "127.0.0.1:6379".parse().unwrap();
+    let listener = match (config.tcp_backlog, config.reuse_port) {
+        (None, false) => TcpListener::bind(addr),
+        (backlog, reuse_port) => {
+            listener::bind(addr, backlog.unwrap_or(listener::DEFAULT_BACKLOG), reuse_port)
+        }
+    }.expect("Failed to bind");
     
     // Non-blocking allows shutdown check every 100ms
     listener.set_nonblocking(true).expect("Cannot set non-blocking");
     
     println!("Server listening...");
-    
-    let restored_map = replay_log().expect("Failed to replay log");
+
+    // A predecessor sharing the port may still be appending to the log
+    if config.reuse_port
+        && let Err(e) = handoff::acquire()
+    {
+        eprintln!("Failed to take over kvstore.log: {e}");
+        std::process::exit(1);
+    }
+
+    let restored_map = replay_log(WAL_PATH, config.max_memory).unwrap_or_else(|e| {
+        eprintln!("Failed to replay log: {e}");
+        std::process::exit(1);
+    });
     println!("Recovered {} keys from log", restored_map.len());
     compact_log(&restored_map).expect("Failed to compact log");
     println!("Log compacted");
@@ -226,9 +3905,16 @@ fn main() {
     let shutdown = Arc::new(AtomicBool::new(false));
     let mut handles = Vec::new();
 
-    // Ctrl+C handler sets shutdown flag
+    // Ctrl+C handler sets shutdown flag. With a lame-duck grace period the
+    // first signal only starts lame-duck mode and a second one stops at once.
     let shutdown_clone = Arc::clone(&shutdown);
     ctrlc::set_handler(move || {
+        if let Some(grace) = lame_duck_grace
+            && enter_lame_duck(&shutdown_clone, grace)
+        {
+            println!("\nShutdown signal received, serving until the grace period ends...");
+            return;
+        }
         println!("\nShutdown signal received...");
         shutdown_clone.store(true, Ordering::Relaxed);
     }).expect("Error setting Ctrl+C handler");
@@ -245,9 +3931,11 @@ fn main() {
                 let db = Arc::clone(&database);
                 let shutdown_flag = Arc::clone(&shutdown);
                 let handle = std::thread::spawn(move || {
+                    CONNECTIONS.fetch_add(1, Ordering::Relaxed);
                     if let Err(e) = handle_client(stream, addr, shutdown_flag, db) {
                         eprintln!("Error handling client: {e}");
                     }
+                    CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
                 });
                 handles.push(handle);
             }
@@ -265,8 +3953,752 @@ fn main() {
         handle.join().unwrap();
     }
 
-    // Final cleanup: compact log before exit
-    let final_map = database.lock().unwrap();
-    compact_log(&final_map).expect("Failed to compact log on shutdown");
+    // Final cleanup: compact log before exit, unless a --reuse-port
+    // successor has taken it over and may be appending to it
+    if handoff::released() {
+        println!("Skipping final compaction, the log belongs to a successor");
+    } else {
+        let final_map = database.lock().unwrap();
+        handoff::while_owner(|| compact_log(&final_map)).expect("Failed to compact log on shutdown");
+    }
     println!("Server shutdown complete");
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Write content to a fresh file for one test; removed on drop
+    struct TempLog(std::path::PathBuf);
+
+    impl TempLog {
+        fn new(name: &str, content: &[u8]) -> TempLog {
+            let path = std::env::temp_dir()
+                .join(format!("kvstore-test-{}-{}.log", std::process::id(), name));
+            std::fs::write(&path, content).unwrap();
+            TempLog(path)
+        }
+
+        fn lines_newest_first(&self) -> Vec<String> {
+            let mut reader = ReverseLogReader::open(self.0.to_str().unwrap()).unwrap();
+            let mut lines = Vec::new();
+            while let Some(line) = reader.next_line().unwrap() {
+                lines.push(line);
+            }
+            lines
+        }
+    }
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn reversed(lines: &[String]) -> Vec<String> {
+        lines.iter().rev().cloned().collect()
+    }
+
+    fn joined(lines: &[String]) -> String {
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+
+    // An empty store for one test. Writes on this thread skip the fsync,
+    // since WAL_PATH is a scratch file.
+    fn store() -> Mutex<HashMap<String, Entry>> {
+        RELAXED_DURABILITY.set(true);
+        Mutex::new(HashMap::new())
+    }
+
+    // A store holding the given string values
+    fn store_with(pairs: &[(&str, &str)]) -> Mutex<HashMap<String, Entry>> {
+        let data = store();
+        for (key, value) in pairs {
+            store_value(&mut data.lock().unwrap(), key.to_string(), Value::from_string(value.to_string()));
+        }
+        data
+    }
+
+    // A store holding one typed value of each kind, under its type name
+    fn store_with_typed() -> Mutex<HashMap<String, Entry>> {
+        let data = store();
+        let mut map = data.lock().unwrap();
+        store_value(&mut map, "hyperloglog".to_string(), Value::Hll(Hll::new()));
+        store_value(&mut map, "bitmap".to_string(), Value::Bitmap(vec![0x80]));
+        store_value(&mut map, "stream".to_string(), Value::Stream(Stream::default()));
+        drop(map);
+        data
+    }
+
+    fn value_of(data: &Mutex<HashMap<String, Entry>>, key: &str) -> Option<String> {
+        data.lock().unwrap().get(key).map(|entry| entry.value.to_string())
+    }
+
+    #[test]
+    fn reverse_reader_empty_file() {
+        let log = TempLog::new("empty", b"");
+        assert!(log.lines_newest_first().is_empty());
+    }
+
+    #[test]
+    fn reverse_reader_file_smaller_than_one_block() {
+        let lines = vec!["first".to_string(), "second".to_string(), "third".to_string()];
+        let log = TempLog::new("small", joined(&lines).as_bytes());
+        assert_eq!(log.lines_newest_first(), reversed(&lines));
+    }
+
+    #[test]
+    fn reverse_reader_file_without_trailing_newline() {
+        let log = TempLog::new("no-newline", b"first\nsecond\nlast");
+        assert_eq!(log.lines_newest_first(), vec!["last", "second", "first"]);
+
+        let log = TempLog::new("single-no-newline", b"only");
+        assert_eq!(log.lines_newest_first(), vec!["only"]);
+    }
+
+    #[test]
+    fn reverse_reader_record_straddling_a_block_boundary() {
+        // The last block starts 100 bytes into the first line
+        let block = REVERSE_READ_BLOCK as usize;
+        let first = "a".repeat(block - 400);
+        let second = "b".repeat(block + 100 - first.len() - 2);
+        let lines = vec![first, second];
+        let content = joined(&lines);
+        assert_eq!(content.len(), block + 100);
+
+        let log = TempLog::new("straddle", content.as_bytes());
+        assert_eq!(log.lines_newest_first(), reversed(&lines));
+    }
+
+    #[test]
+    fn reverse_reader_line_ending_exactly_on_a_block_boundary() {
+        let block = REVERSE_READ_BLOCK as usize;
+        let lines = vec!["x".repeat(99), "y".repeat(block - 1)];
+        let log = TempLog::new("boundary", joined(&lines).as_bytes());
+        assert_eq!(log.lines_newest_first(), reversed(&lines));
+    }
+
+    #[test]
+    fn reverse_reader_line_longer_than_several_blocks() {
+        let block = REVERSE_READ_BLOCK as usize;
+        let lines = vec!["head".to_string(), "z".repeat(block * 3 + 17), "tail".to_string()];
+        let log = TempLog::new("long-line", joined(&lines).as_bytes());
+        assert_eq!(log.lines_newest_first(), reversed(&lines));
+    }
+
+    #[test]
+    fn reverse_reader_many_lines_across_blocks() {
+        let lines: Vec<String> = (0..3000).map(|i| format!("{}:{}", i, "v".repeat(i % 37))).collect();
+        let log = TempLog::new("many", joined(&lines).as_bytes());
+        assert_eq!(log.lines_newest_first(), reversed(&lines));
+    }
+
+    #[test]
+    fn reverse_reader_yields_records_newest_first_and_skips_corrupt_ones() {
+        let content = concat!(
+            "{\"SET\":{\"key\":\"a\",\"value\":\"1\"}}\n",
+            "not json\n",
+            "{\"ts\":5,\"seq\":2,\"DELETE\":{\"key\":\"a\"}}\n",
+        );
+        let log = TempLog::new("records", content.as_bytes());
+        let reader = ReverseLogReader::open(log.0.to_str().unwrap()).unwrap();
+        let records: Vec<LogRecord<Command>> = reader.map(Result::unwrap).collect();
+
+        assert_eq!(records.len(), 2);
+        assert!(matches!(&records[0].command, Command::DELETE { key } if key == "a"));
+        assert_eq!((records[0].ts, records[0].seq), (Some(5), Some(2)));
+        assert!(matches!(&records[1].command, Command::SET { key, .. } if key == "a"));
+        assert_eq!(records[1].seq, None);
+    }
+
+    #[test]
+    fn canonical_integer_strings_are_stored_as_int() {
+        assert_eq!(Value::from_string("42".to_string()), Value::Int(42));
+        assert_eq!(Value::from_string("-7".to_string()), Value::Int(-7));
+        assert_eq!(Value::from_string(i64::MIN.to_string()), Value::Int(i64::MIN));
+        for text in ["007", "+1", "-0", "1.0", " 1", "9223372036854775808", ""] {
+            assert!(matches!(Value::from_string(text.to_string()), Value::Str(_)), "{text:?}");
+        }
+        assert_eq!(Value::from_string("007".to_string()).to_string(), "007");
+    }
+
+    #[test]
+    fn incr_by_rejects_non_integers_and_overflow() {
+        assert_eq!(Value::Int(41).incr_by(1), Ok(42));
+        assert_eq!(Value::Int(i64::MIN + 1).incr_by(-1), Ok(i64::MIN));
+        assert!(Value::Int(i64::MAX).incr_by(1).unwrap_err().contains("overflow"));
+        assert!(Value::Int(i64::MIN).incr_by(-1).unwrap_err().contains("overflow"));
+        assert!(Value::from_string("abc".to_string()).incr_by(1).unwrap_err().contains("not an integer"));
+    }
+
+    #[test]
+    fn incr_and_decr_create_update_and_refuse_overflow() {
+        let data = store_with(&[("max", &i64::MAX.to_string()), ("text", "abc")]);
+        assert_eq!(apply_incr(&data, "counter".to_string(), 1).unwrap(), "1\n");
+        assert_eq!(apply_incr(&data, "counter".to_string(), 1).unwrap(), "2\n");
+        assert_eq!(apply_incr(&data, "fresh".to_string(), -1).unwrap(), "-1\n");
+
+        let reply = apply_incr(&data, "max".to_string(), 1).unwrap();
+        assert!(reply.starts_with("ERROR:") && reply.contains("overflow"));
+        assert_eq!(value_of(&data, "max"), Some(i64::MAX.to_string()));
+        assert!(apply_incr(&data, "text".to_string(), 1).unwrap().starts_with("ERROR:"));
+        assert_eq!(value_of(&data, "text"), Some("abc".to_string()));
+    }
+
+    #[test]
+    fn incr_and_decr_take_exactly_one_key() {
+        assert!(matches!(parse_command("INCR a"), Ok(Command::INCR { key }) if key == "a"));
+        assert!(matches!(parse_command("DECR a"), Ok(Command::DECR { key }) if key == "a"));
+        assert!(parse_command("INCR").is_err());
+        assert!(parse_command("DECR a b").is_err());
+    }
+
+    // Every key a full SCANVALUE scan of data returns, in order
+    fn scan_all(data: &Mutex<HashMap<String, Entry>>, pattern: &str) -> Vec<String> {
+        let mut found = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (next, keys) = scanvalue(data, cursor.as_deref(), pattern).unwrap();
+            found.extend(keys);
+            if next == "0" {
+                return found;
+            }
+            cursor = scanvalue_cursor(&next).unwrap();
+        }
+    }
+
+    #[test]
+    fn scanvalue_visits_keys_in_order_across_batches() {
+        let data = store();
+        let mut expected = Vec::new();
+        for i in 0..SCANVALUE_BATCH * 2 + 7 {
+            let key = format!("key{:04}", i);
+            let value = if i % 3 == 0 { "match" } else { "other" };
+            store_value(&mut data.lock().unwrap(), key.clone(), Value::from_string(value.to_string()));
+            if i % 3 == 0 {
+                expected.push(key);
+            }
+        }
+        assert_eq!(scan_all(&data, "mat*"), expected);
+    }
+
+    #[test]
+    fn scanvalue_cursor_survives_a_rehash() {
+        let data = store();
+        for i in 0..SCANVALUE_BATCH * 2 {
+            store_value(&mut data.lock().unwrap(), format!("old{:04}", i), Value::Int(1));
+        }
+        let (next, first) = scanvalue(&data, None, "1").unwrap();
+        assert_eq!(first.len(), SCANVALUE_BATCH);
+
+        // Enough inserts to grow the table and reorder its iteration
+        for i in 0..SCANVALUE_BATCH * 50 {
+            store_value(&mut data.lock().unwrap(), format!("new{:05}", i), Value::Int(2));
+        }
+        let mut cursor = scanvalue_cursor(&next).unwrap();
+        let mut rest = Vec::new();
+        loop {
+            let (next, keys) = scanvalue(&data, cursor.as_deref(), "1").unwrap();
+            rest.extend(keys);
+            if next == "0" {
+                break;
+            }
+            cursor = scanvalue_cursor(&next).unwrap();
+        }
+        let mut all = first;
+        all.extend(rest);
+        let expected: Vec<String> = (0..SCANVALUE_BATCH * 2).map(|i| format!("old{:04}", i)).collect();
+        assert_eq!(all, expected);
+    }
+
+    #[test]
+    fn scanvalue_matches_only_string_and_integer_values() {
+        let data = store_with(&[("s", "abc"), ("n", "123")]);
+        store_value(&mut data.lock().unwrap(), "h".to_string(), Value::Hll(Hll::new()));
+        assert_eq!(scan_all(&data, "*"), vec!["n", "s"]);
+        assert_eq!(scan_all(&data, "1*"), vec!["n"]);
+        assert!(scan_all(&store(), "*").is_empty());
+    }
+
+    #[test]
+    fn scanvalue_cursor_is_zero_or_an_encoded_key() {
+        assert_eq!(scanvalue_cursor("0"), Some(None));
+        let cursor = Codec::Base64.encode("user:1");
+        assert_eq!(scanvalue_cursor(&cursor), Some(Some("user:1".to_string())));
+        assert_eq!(scanvalue_cursor("12"), None);
+        assert_eq!(scanvalue_cursor("not base64!"), None);
+        assert!(parse_command("SCANVALUE 5 *").is_err());
+    }
+
+    #[test]
+    fn flush_bulk_logs_and_applies_pending_sets() {
+        let data = Arc::new(store());
+        let mut load = BulkLoad { data: Arc::clone(&data), pending: Vec::new(), loaded: 0 };
+        for (key, value) in [("bulk-a", "1"), ("bulk-b", "two")] {
+            UNLOGGED_BULK_SETS.fetch_add(1, Ordering::SeqCst);
+            load.pending.push(Command::SET {
+                key: key.to_string(),
+                value: value.to_string(),
+                kind: None,
+                get: false,
+            });
+        }
+        flush_bulk(&data, &mut load).unwrap();
+        assert!(load.pending.is_empty());
+        assert_eq!(load.loaded, 2);
+        assert_eq!(value_of(&data, "bulk-a"), Some("1".to_string()));
+        assert_eq!(value_of(&data, "bulk-b"), Some("two".to_string()));
+        assert_eq!(UNLOGGED_BULK_SETS.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn history_lists_every_mutation_of_a_key_newest_first() {
+        let lines = [
+            r#"{"ts":1,"SET":{"key":"k","value":"v1"}}"#,
+            r#"{"ts":2,"SET":{"key":"other","value":"x"}}"#,
+            r#"{"ts":3,"PFADD":{"key":"k","elements":["a","b"]}}"#,
+            r#"{"ts":4,"SETBIT":{"key":"k","offset":7,"bit":true}}"#,
+            r#"{"ts":5,"BITOP":{"op":"Or","dest":"k","sources":["s1","s2"]}}"#,
+            r#"{"ts":6,"PFMERGE":{"dest":"k","sources":["h"]}}"#,
+            r#"{"ts":7,"XADD":{"key":"k","id":{"ms":1,"seq":0},"fields":[["f","v"]]}}"#,
+            r#"{"ts":8,"XGROUP":{"key":"k","group":"g","id":{"ms":0,"seq":0}}}"#,
+            r#"{"ts":9,"XREADGROUP":{"group":"g","consumer":"c","key":"k","id":null,"count":null}}"#,
+            r#"{"ts":10,"XACK":{"key":"k","group":"g","ids":[{"ms":1,"seq":0}]}}"#,
+            r#"{"ts":11,"SET":{"key":"k","value":"STRM{}","type":"stream"}}"#,
+            r#"{"ts":12,"DELETE":{"key":"k"}}"#,
+        ];
+        let log = TempLog::new("history", joined(&lines.map(String::from)).as_bytes());
+        let history = key_history(log.0.to_str().unwrap(), "k", 100).unwrap();
+        assert_eq!(
+            history,
+            vec![
+                "12 DELETE",
+                "11 SET (stream)",
+                "10 XACK g 1-0",
+                "9 XREADGROUP g c",
+                "8 XGROUP g 0-0",
+                "7 XADD 1-0 f v",
+                "6 PFMERGE h",
+                "5 BITOP OR s1 s2",
+                "4 SETBIT 7 1",
+                "3 PFADD a b",
+                "1 SET v1",
+            ]
+        );
+        assert_eq!(key_history(log.0.to_str().unwrap(), "k", 2).unwrap(), vec!["12 DELETE", "11 SET (stream)"]);
+    }
+
+    #[test]
+    fn history_skips_corrupt_lines_but_reports_an_unreadable_log() {
+        let log = TempLog::new("history-corrupt", b"{\"SET\":{\"key\":\"k\",\"value\":\"v\"}}\n\xff{not json\n");
+        assert_eq!(key_history(log.0.to_str().unwrap(), "k", 10).unwrap(), vec!["- SET v"]);
+
+        // A directory opens but cannot be read
+        let error = key_history(std::env::temp_dir().to_str().unwrap(), "k", 10).unwrap_err();
+        assert!(error.starts_with("ERROR:"), "{error}");
+
+        let missing = std::env::temp_dir().join("kvstore-test-no-such.log");
+        assert!(key_history(missing.to_str().unwrap(), "k", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn history_max_flag_must_be_positive() {
+        let parse = |args: &[&str]| Config::from_args(args.iter().map(|s| s.to_string()));
+        assert_eq!(parse(&["--history-max", "500"]).unwrap().history_max, Some(500));
+        assert!(parse(&["--history-max", "0"]).is_err());
+        assert!(parse(&["--history-max"]).is_err());
+    }
+
+    #[test]
+    fn getrange_clamps_and_counts_negative_offsets_from_the_end() {
+        let data = store_with(&[("s", "Hello, world"), ("n", "12345")]);
+        let range = |key: &str, start, end| getrange(&data, key, start, end).unwrap();
+        assert_eq!(range("s", 0, 4), "Hello");
+        assert_eq!(range("s", -5, -1), "world");
+        assert_eq!(range("s", -100, 2), "Hel");
+        assert_eq!(range("s", 7, 1000), "world");
+        assert_eq!(range("s", 5, 2), "");
+        assert_eq!(range("s", 100, 200), "");
+        assert_eq!(range("n", 1, 2), "23");
+        assert_eq!(range("missing", 0, -1), "");
+    }
+
+    #[test]
+    fn getrange_rejects_typed_values() {
+        let data = store_with_typed();
+        for key in ["hyperloglog", "bitmap", "stream"] {
+            assert_eq!(getrange(&data, key, 0, -1), Err(WRONGTYPE.to_string()), "{key}");
+        }
+    }
+
+    #[test]
+    fn streams_cannot_be_read_as_strings() {
+        let data = store_with_typed();
+        let map = data.lock().unwrap();
+        assert_eq!(string_value(&map["stream"].value), Err(WRONGTYPE.to_string()));
+        assert!(string_value(&map["hyperloglog"].value).is_ok());
+        assert!(string_value(&map["bitmap"].value).is_ok());
+        assert_eq!(string_value(&Value::Int(5)), Ok("5".to_string()));
+    }
+
+    #[test]
+    fn bget_and_getorset_refuse_to_reply_with_a_stream() {
+        let data = store_with_typed();
+        let shutdown = AtomicBool::new(false);
+        let now = Some(Duration::ZERO);
+        assert_eq!(bget(&data, "stream", now, &shutdown), Err(WRONGTYPE.to_string()));
+        assert_eq!(bget(&data, "missing", now, &shutdown), Ok(None));
+
+        let reply = apply_getorset(&data, "stream".to_string(), "default".to_string()).unwrap();
+        assert_eq!(reply, format!("{}\n", WRONGTYPE));
+        assert!(matches!(data.lock().unwrap()["stream"].value, Value::Stream(_)));
+    }
+
+    #[test]
+    fn cadel_deletes_only_on_a_matching_string() {
+        let data = store_with(&[("k", "v"), ("n", "10")]);
+        assert_eq!(apply_cadel(&data, "k".to_string(), "other".to_string()).unwrap(), "0\n");
+        assert_eq!(apply_cadel(&data, "k".to_string(), "v".to_string()).unwrap(), "1\n");
+        assert_eq!(value_of(&data, "k"), None);
+        assert_eq!(apply_cadel(&data, "k".to_string(), "v".to_string()).unwrap(), "0\n");
+        assert_eq!(apply_cadel(&data, "n".to_string(), "10".to_string()).unwrap(), "1\n");
+    }
+
+    #[test]
+    fn cadel_rejects_typed_values_even_given_their_encoding() {
+        let data = store_with_typed();
+        for key in ["hyperloglog", "bitmap", "stream"] {
+            let encoded = value_of(&data, key).unwrap();
+            let reply = apply_cadel(&data, key.to_string(), encoded).unwrap();
+            assert_eq!(reply, format!("{}\n", WRONGTYPE), "{key}");
+            assert!(value_of(&data, key).is_some());
+        }
+    }
+
+    #[test]
+    fn cycle_advances_through_the_ring_and_wraps() {
+        let data = store_with(&[("off-ring", "purple")]);
+        let ring = || vec!["red".to_string(), "green".to_string(), "blue".to_string()];
+        let cycle = |key: &str| apply_cycle(&data, key.to_string(), ring()).unwrap();
+        assert_eq!(cycle("light"), "red\n");
+        assert_eq!(cycle("light"), "green\n");
+        assert_eq!(cycle("light"), "blue\n");
+        assert_eq!(cycle("light"), "red\n");
+        assert_eq!(cycle("off-ring"), "red\n");
+        assert!(parse_command("CYCLE light").is_err());
+    }
+
+    #[test]
+    fn cycle_leaves_typed_values_alone() {
+        let data = store_with_typed();
+        for key in ["hyperloglog", "bitmap", "stream"] {
+            let reply = apply_cycle(&data, key.to_string(), vec!["a".to_string()]).unwrap();
+            assert_eq!(reply, format!("{}\n", WRONGTYPE), "{key}");
+            assert!(!matches!(data.lock().unwrap()[key].value, Value::Str(_)));
+        }
+    }
+
+    #[test]
+    fn setif_applies_every_pair_only_when_the_condition_matches() {
+        let data = store_with(&[("cond", "go")]);
+        let pairs = || vec![("a".to_string(), "1".to_string()), ("b".to_string(), "2".to_string())];
+        assert_eq!(apply_setif(&data, "cond".to_string(), "stop".to_string(), pairs()).unwrap(), "0\n");
+        assert_eq!(value_of(&data, "a"), None);
+        assert_eq!(apply_setif(&data, "missing".to_string(), "go".to_string(), pairs()).unwrap(), "0\n");
+        assert_eq!(apply_setif(&data, "cond".to_string(), "go".to_string(), pairs()).unwrap(), "1\n");
+        assert_eq!((value_of(&data, "a"), value_of(&data, "b")), (Some("1".to_string()), Some("2".to_string())));
+
+        assert!(parse_command("SETIF cond go a").is_err());
+        assert!(matches!(parse_command("SETIF cond go a 1 b 2"), Ok(Command::SETIF { pairs, .. }) if pairs.len() == 2));
+    }
+
+    #[test]
+    fn setif_condition_on_a_typed_value_is_wrongtype() {
+        let data = store_with_typed();
+        for key in ["hyperloglog", "bitmap", "stream"] {
+            let encoded = value_of(&data, key).unwrap();
+            let pairs = vec![("target".to_string(), "x".to_string())];
+            let reply = apply_setif(&data, key.to_string(), encoded, pairs).unwrap();
+            assert_eq!(reply, format!("{}\n", WRONGTYPE), "{key}");
+        }
+        assert_eq!(value_of(&data, "target"), None);
+    }
+
+    #[test]
+    fn wal_sync_latency_is_recorded_only_when_an_fsync_runs() {
+        latency::set_threshold(Duration::ZERO);
+        let syncs = || latency::history().iter().filter(|event| event.cause == "wal-sync").count();
+        let delete = |key: &str| Command::DELETE { key: key.to_string() };
+
+        RELAXED_DURABILITY.set(true);
+        let before = (syncs(), latency::fsync_summary().samples);
+        write_to_log(&delete("latency-relaxed")).unwrap();
+        assert_eq!((syncs(), latency::fsync_summary().samples), before);
+
+        RELAXED_DURABILITY.set(false);
+        write_to_log(&delete("latency-fsync")).unwrap();
+        assert_eq!((syncs(), latency::fsync_summary().samples), (before.0 + 1, before.1 + 1));
+    }
+
+    #[test]
+    fn scanvalue_reply_under_maxreply_rewinds_the_cursor() {
+        let keys: Vec<String> = ["alpha", "beta", "gamma"].map(String::from).to_vec();
+        let next = Codec::Base64.encode("zeta");
+        let full = format!("{}\n3\nalpha\nbeta\ngamma\n", next);
+        assert_eq!(scanvalue_reply("0", next.clone(), &keys, None), full);
+        assert_eq!(scanvalue_reply("0", next.clone(), &keys, Some(full.len())), full);
+
+        let capped = scanvalue_reply("0", next.clone(), &keys, Some(full.len() - 1));
+        let beta = Codec::Base64.encode("beta");
+        assert_eq!(capped, format!("{}\n2\nalpha\nbeta\nTRUNCATED\n", beta));
+
+        // Nothing fits: keep the given cursor so the call can be retried
+        assert_eq!(scanvalue_reply("Z2l2ZW4=", next.clone(), &keys, Some(1)), "Z2l2ZW4=\n0\nTRUNCATED\n");
+        assert_eq!(scanvalue_reply("0", "0".to_string(), &[], Some(1)), "0\n0\n");
+    }
+
+    #[test]
+    fn cap_reply_keeps_whole_lines_and_marks_the_cut() {
+        let reply = "3\n1-0 a\n2-0 bb\n3-0 ccc\n".to_string();
+        assert_eq!(cap_reply(reply.clone(), None, true), reply);
+        assert_eq!(cap_reply(reply.clone(), Some(reply.len()), true), reply);
+        assert_eq!(cap_reply(reply.clone(), Some(15), true), "2\n1-0 a\n2-0 bb\nTRUNCATED 2-0\n");
+        assert_eq!(cap_reply(reply.clone(), Some(15), false), "2\n1-0 a\n2-0 bb\nTRUNCATED\n");
+        assert_eq!(cap_reply(reply.clone(), Some(1), true), "0\nTRUNCATED\n");
+
+        // Replies without a count line pass through
+        let error = "ERROR: WRONGTYPE something long\n".to_string();
+        assert_eq!(cap_reply(error.clone(), Some(4), false), error);
+    }
+
+    #[test]
+    fn decrfloor_deducts_at_most_what_the_counter_holds() {
+        let data = store_with(&[("n", "10"), ("neg", "-3"), ("s", "abc")]);
+        let decrfloor = |key: &str, amount| apply_decrfloor(&data, key.to_string(), amount).unwrap();
+        assert_eq!(decrfloor("n", 4), "4\n");
+        assert_eq!(value_of(&data, "n"), Some("6".to_string()));
+        assert_eq!(decrfloor("n", 100), "6\n");
+        assert_eq!(value_of(&data, "n"), Some("0".to_string()));
+        assert_eq!(decrfloor("n", 1), "0\n");
+
+        // Nothing to take from a missing or negative counter, and nothing is written
+        assert_eq!(decrfloor("missing", 5), "0\n");
+        assert_eq!(value_of(&data, "missing"), None);
+        assert_eq!(decrfloor("neg", 5), "0\n");
+        assert_eq!(value_of(&data, "neg"), Some("-3".to_string()));
+        assert!(decrfloor("s", 1).starts_with("ERROR"));
+
+        assert!(parse_command("DECRFLOOR n -1").is_err());
+        assert!(matches!(parse_command("DECRFLOOR n 0"), Ok(Command::DECRFLOOR { amount: 0, .. })));
+    }
+
+    #[test]
+    fn clampincr_applies_only_within_bounds() {
+        let data = store_with(&[("n", "8"), ("s", "abc")]);
+        let clampincr = |key: &str, delta| apply_clampincr(&data, key.to_string(), delta, 0, 10).unwrap();
+        assert_eq!(clampincr("n", 2), "1\n10\n");
+        assert_eq!(clampincr("n", 1), "0\n");
+        assert_eq!(value_of(&data, "n"), Some("10".to_string()));
+        assert_eq!(clampincr("n", -10), "1\n0\n");
+        assert_eq!(clampincr("missing", -1), "0\n");
+        assert_eq!(value_of(&data, "missing"), None);
+        assert_eq!(clampincr("missing", 3), "1\n3\n");
+        assert!(clampincr("s", 1).starts_with("ERROR"));
+
+        // Overflow is an error, not a rejection
+        let max = Value::Int(i64::MAX);
+        assert!(clampincr_value(Some(&max), 1, i64::MIN, i64::MAX).is_err());
+
+        assert!(parse_command("CLAMPINCR n 1 10 0").is_err());
+        assert!(matches!(parse_command("CLAMPINCR n 1 5 5"), Ok(Command::CLAMPINCR { min: 5, max: 5, .. })));
+    }
+
+    #[test]
+    fn ratelimit_counts_within_a_fixed_window_and_restarts_after_it() {
+        assert_eq!(ratelimit_value(None, 60, 125), Ok((120, 1)));
+        let current = Value::from_string("120:4".to_string());
+        assert_eq!(ratelimit_value(Some(&current), 60, 179), Ok((120, 5)));
+        assert_eq!(ratelimit_value(Some(&current), 60, 180), Ok((180, 1)));
+        for bad in ["5", "x:1", "120:", "abc"] {
+            let value = Value::from_string(bad.to_string());
+            assert!(ratelimit_value(Some(&value), 60, 125).is_err(), "{bad}");
+        }
+
+        let data = store();
+        let hit = || apply_ratelimit(&data, "rl".to_string(), 2, 3600).unwrap();
+        assert!(hit().ends_with("\n0\n"));
+        assert!(hit().ends_with("\n0\n"));
+        // A third hit in the same hour is over the limit, unless the hour just turned
+        let third = hit();
+        assert!(third == "3\n1\n" || third == "1\n0\n", "{third}");
+
+        assert!(parse_command("RATELIMIT rl 5 0").is_err());
+    }
+
+    #[test]
+    fn setver_writes_only_at_the_expected_version() {
+        let data = store();
+        let setver = |value: &str, expected| apply_setver(&data, "k".to_string(), value.to_string(), expected).unwrap();
+        assert_eq!(setver("a", 1), "ERROR: version mismatch, current version is 0\n");
+        assert_eq!(setver("a", 0), "1\n");
+        assert_eq!(setver("b", 0), "ERROR: version mismatch, current version is 1\n");
+        assert_eq!(setver("b", 1), "2\n");
+        assert_eq!(value_of(&data, "k"), Some("b".to_string()));
+
+        assert!(parse_command("SETVER k v -1").is_err());
+        assert!(matches!(parse_command("SETVER k v 3"), Ok(Command::SETVER { expected: 3, .. })));
+    }
+
+    #[test]
+    fn setrange_pads_with_zero_bytes_and_caps_the_offset() {
+        let data = store_with(&[("s", "Hello World"), ("n", "12345")]);
+        let setrange = |key: &str, offset, value: &str| apply_setrange(&data, key.to_string(), offset, value.to_string()).unwrap();
+        assert_eq!(setrange("s", 6, "Redis"), "11\n");
+        assert_eq!(value_of(&data, "s"), Some("Hello Redis".to_string()));
+        assert_eq!(setrange("missing", 3, "x"), "4\n");
+        assert_eq!(value_of(&data, "missing"), Some("\0\0\0x".to_string()));
+        assert_eq!(setrange("n", 0, "9"), "5\n");
+        assert_eq!(value_of(&data, "n"), Some("92345".to_string()));
+
+        // An empty value neither pads nor creates the key
+        assert_eq!(setrange("s", 100, ""), "11\n");
+        assert_eq!(setrange("absent", 100, ""), "0\n");
+        assert_eq!(value_of(&data, "absent"), None);
+
+        assert!(setrange_value(Some(&Value::from_string("é".to_string())), 1, "x").is_err());
+        assert_eq!(setrange_value(Some(&Value::Bitmap(vec![1])), 0, "x"), Err(WRONGTYPE.to_string()));
+
+        assert!(parse_command(&format!("SETRANGE k {} x", MAX_SETRANGE_OFFSET)).is_ok());
+        assert!(parse_command(&format!("SETRANGE k {} x", MAX_SETRANGE_OFFSET + 1)).is_err());
+        assert!(parse_command("SETRANGE k -1 x").is_err());
+    }
+
+    #[test]
+    fn swapkeys_exchanges_values_and_moves_a_lone_value() {
+        let data = store_with(&[("a", "1"), ("b", "two")]);
+        let swapkeys = |key1: &str, key2: &str| apply_swapkeys(&data, key1.to_string(), key2.to_string()).unwrap();
+        assert_eq!(swapkeys("a", "b"), "OK\n");
+        assert_eq!((value_of(&data, "a"), value_of(&data, "b")), (Some("two".to_string()), Some("1".to_string())));
+        assert_eq!(data.lock().unwrap()["b"].value, Value::Int(1));
+
+        assert_eq!(swapkeys("a", "c"), "OK\n");
+        assert_eq!((value_of(&data, "a"), value_of(&data, "c")), (None, Some("two".to_string())));
+        assert_eq!(swapkeys("x", "y"), "OK\n");
+        assert_eq!(swapkeys("b", "b"), "OK\n");
+        assert_eq!(value_of(&data, "b"), Some("1".to_string()));
+        assert!(!data.lock().unwrap().contains_key("x"));
+    }
+
+    #[test]
+    fn getorset_sets_the_default_only_when_absent() {
+        let data = store_with(&[("k", "current")]);
+        let getorset = |key: &str, default: &str| apply_getorset(&data, key.to_string(), default.to_string()).unwrap();
+        assert_eq!(getorset("k", "default"), "current\n");
+        assert_eq!(value_of(&data, "k"), Some("current".to_string()));
+        assert_eq!(getorset("new", "first"), "first\n");
+        assert_eq!(getorset("new", "second"), "first\n");
+        assert_eq!(data.lock().unwrap()["new"].version, 1);
+
+        assert!(parse_command("GETORSET k v EX 10").is_err());
+        assert!(parse_command("GETORSET k").is_err());
+    }
+
+    #[test]
+    fn initif_claims_only_an_empty_store() {
+        let data = store();
+        assert_eq!(apply_initif(&data, "seed".to_string(), "1".to_string()).unwrap(), "1\n");
+        assert_eq!(apply_initif(&data, "seed".to_string(), "2".to_string()).unwrap(), "0\n");
+        assert_eq!(apply_initif(&data, "other".to_string(), "3".to_string()).unwrap(), "0\n");
+        assert_eq!(value_of(&data, "seed"), Some("1".to_string()));
+        assert_eq!(value_of(&data, "other"), None);
+    }
+
+    #[test]
+    fn renamed_commands_resolve_only_under_their_new_name() {
+        let pair = |original: &str, new_name: &str| (original.to_string(), new_name.to_string());
+        let table = rename_table(&[pair("SELFTEST", "NUKE"), pair("SHUTDOWN", "")]).unwrap();
+        assert_eq!(resolve_renamed(&table, "nuke"), Some("SELFTEST".to_string()));
+        assert_eq!(resolve_renamed(&table, "SELFTEST"), None);
+        assert_eq!(resolve_renamed(&table, "shutdown"), None);
+        assert_eq!(resolve_renamed(&table, "get"), Some("GET".to_string()));
+
+        // Two commands may trade names
+        let table = rename_table(&[pair("GET", "SET"), pair("SET", "GET")]).unwrap();
+        assert_eq!(resolve_renamed(&table, "SET"), Some("GET".to_string()));
+
+        assert!(rename_table(&[pair("NOPE", "X")]).is_err());
+        assert!(rename_table(&[pair("SELFTEST", "GET")]).is_err());
+        assert!(rename_table(&[pair("SELFTEST", "X"), pair("SHUTDOWN", "X")]).is_err());
+        assert!(rename_table(&[pair("SELFTEST", ""), pair("SHUTDOWN", "")]).is_ok());
+    }
+
+    #[test]
+    fn dry_run_validation_reports_errors_without_writing() {
+        let data = store_with(&[("max", &i64::MAX.to_string()), ("s", "abc")]);
+        let validate = |line: &str| validate_write(&data, &parse_command(line).unwrap());
+        assert!(validate("INCR max").is_err());
+        assert!(validate("DECR max").is_ok());
+        assert!(validate("INCR s").is_err());
+        assert!(validate("DECRFLOOR s 1").is_err());
+        assert!(validate("PFADD s a").is_err());
+        assert!(validate("SETBIT s 0 1").is_err());
+        assert_eq!(validate("SETVER s v 0"), Err("ERROR: version mismatch, current version is 1".to_string()));
+        assert!(validate("SETVER s v 1").is_ok());
+        assert!(validate("SET s other").is_ok());
+        assert!(validate("INCR fresh").is_ok());
+
+        assert_eq!(value_of(&data, "s"), Some("abc".to_string()));
+        assert_eq!(value_of(&data, "fresh"), None);
+    }
+
+    #[test]
+    fn replay_decodes_only_values_logged_with_their_type() {
+        let hll = Value::Hll(Hll::new()).to_string();
+        let content = format!(
+            concat!(
+                "{{\"SET\":{{\"key\":\"hll\",\"value\":\"{}\",\"type\":\"hyperloglog\"}}}}\n",
+                "{{\"SET\":{{\"key\":\"plain\",\"value\":\"{}\"}}}}\n",
+                "{{\"SET\":{{\"key\":\"bad\",\"value\":\"not a sketch\",\"type\":\"hyperloglog\"}}}}\n",
+            ),
+            hll, hll,
+        );
+        let log = TempLog::new("replay-typed", content.as_bytes());
+        let map = replay_log(log.0.to_str().unwrap(), None).unwrap();
+
+        assert!(matches!(map["hll"].value, Value::Hll(_)));
+        // A string that merely looks like a sketch stays a string
+        assert!(matches!(map["plain"].value, Value::Str(_)));
+        assert!(matches!(map["bad"].value, Value::Str(_)));
+    }
+
+    #[test]
+    fn replay_restores_the_sequence_and_snapshot_versions() {
+        let content = concat!(
+            "{\"LASTSEQ\":{\"seq\":7000000}}\n",
+            "{\"SNAPSHOT\":{\"key\":\"snap\",\"value\":\"v\",\"version\":4}}\n",
+            "{\"ts\":1,\"seq\":7000001,\"SET\":{\"key\":\"snap\",\"value\":\"w\"}}\n",
+            "torn {\"SET\n",
+            "{\"ts\":1,\"seq\":7000002,\"DELETE\":{\"key\":\"gone\"}}\n",
+        );
+        let log = TempLog::new("replay-seq", content.as_bytes());
+        let map = replay_log(log.0.to_str().unwrap(), None).unwrap();
+
+        assert_eq!((map["snap"].value.to_string(), map["snap"].version), ("w".to_string(), 5));
+        // Other tests log writes too, so the sequence may have moved on since
+        assert!(OP_SEQ.load(Ordering::Relaxed) >= 7000002);
+    }
+
+    #[test]
+    fn replay_refuses_a_dataset_over_the_memory_limit() {
+        let content: String = (0..100)
+            .map(|i| format!("{{\"SET\":{{\"key\":\"k{}\",\"value\":\"{}\"}}}}\n", i, "x".repeat(100)))
+            .collect();
+        let log = TempLog::new("maxmemory", content.as_bytes());
+        let path = log.0.to_str().unwrap();
+        assert!(replay_log(path, None).is_ok());
+        assert!(replay_log(path, Some(1_000_000)).is_ok());
+        let error = replay_log(path, Some(1000)).unwrap_err();
+        assert!(error.to_string().contains("exceeds memory limit"), "{error}");
+
+        assert!(replay_log("target/no-such-kvstore.log", Some(1)).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,197 @@
+// File-backed storage for large string values under --mmap-values. Values
+// of at least MIN_VALUE_LEN bytes are copied into shared memory mappings of
+// a scratch file instead of the heap, so the kernel can write cold ones
+// back to disk and drop them from memory under pressure.
+//
+// The file is only a cache: it is unlinked as soon as it is created, so it
+// never outlives the process, and the WAL stays the durable copy that
+// startup replay rebuilds it from. Space is handed out from fixed-size
+// segments that stay mapped for the life of the process. A segment is
+// reused once every value in it has been dropped, so overwritten values
+// only waste space until the rest of their segment is gone too.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::ops::Deref;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex, OnceLock};
+
+// Smaller values stay on the heap, where they are cheaper to reach
+pub const MIN_VALUE_LEN: usize = 4096;
+
+// Size of each mapping; larger values also stay on the heap
+const SEGMENT_SIZE: usize = 64 << 20;
+
+static STORE: OnceLock<Mutex<Store>> = OnceLock::new();
+
+struct Store {
+    file: File,
+    segments: Vec<Segment>,
+    // Segment being filled and the bytes used in it
+    current: usize,
+    used: usize,
+    // Segments holding no live values, ready to be filled again
+    free: Vec<usize>,
+}
+
+struct Segment {
+    base: *mut u8,
+    live_bytes: usize,
+    live_values: usize,
+}
+
+// The mappings are only written under the store lock, into space no live
+// value refers to
+unsafe impl Send for Store {}
+
+pub fn enable() -> io::Result<()> {
+    let path = format!("kvstore.values.{}", std::process::id());
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)?;
+    std::fs::remove_file(&path)?;
+
+    let mut store = Store { file, segments: Vec::new(), current: 0, used: 0, free: Vec::new() };
+    store.add_segment()?;
+    let _ = STORE.set(Mutex::new(store));
+    Ok(())
+}
+
+// Copy value into the mapped file; None if mapping is off, the value is
+// outside the size bounds, or the file cannot grow
+pub fn store(value: &str) -> Option<MappedStr> {
+    if value.len() < MIN_VALUE_LEN || value.len() > SEGMENT_SIZE {
+        return None;
+    }
+    let mut store = STORE.get()?.lock().unwrap();
+
+    if store.used + value.len() > SEGMENT_SIZE {
+        let next = match store.free.pop() {
+            Some(index) => index,
+            None => match store.add_segment() {
+                Ok(index) => index,
+                Err(e) => {
+                    eprintln!("Warning: cannot grow mapped value file, keeping value on heap: {e}");
+                    return None;
+                }
+            },
+        };
+        store.current = next;
+        store.used = 0;
+    }
+
+    let (segment, offset) = (store.current, store.used);
+    let ptr = unsafe {
+        let ptr = store.segments[segment].base.add(offset);
+        std::ptr::copy_nonoverlapping(value.as_ptr(), ptr, value.len());
+        ptr
+    };
+    store.used += value.len();
+    store.segments[segment].live_bytes += value.len();
+    store.segments[segment].live_values += 1;
+
+    Some(MappedStr(Arc::new(Mapping { ptr, len: value.len(), segment })))
+}
+
+// Bytes of live values in the file and the number of segments mapped
+pub fn usage() -> (usize, usize) {
+    STORE.get().map_or((0, 0), |store| {
+        let store = store.lock().unwrap();
+        (store.segments.iter().map(|s| s.live_bytes).sum(), store.segments.len())
+    })
+}
+
+impl Store {
+    // Extend the file by one segment and map it; returns its index
+    fn add_segment(&mut self) -> io::Result<usize> {
+        let index = self.segments.len();
+        let offset = index * SEGMENT_SIZE;
+        self.file.set_len((offset + SEGMENT_SIZE) as u64)?;
+
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                SEGMENT_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                self.file.as_raw_fd(),
+                offset as libc::off_t,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.segments.push(Segment { base: base as *mut u8, live_bytes: 0, live_values: 0 });
+        Ok(index)
+    }
+
+    fn release(&mut self, mapping: &Mapping) {
+        let segment = &mut self.segments[mapping.segment];
+        segment.live_bytes -= mapping.len;
+        segment.live_values -= 1;
+        if segment.live_values > 0 {
+            return;
+        }
+        if mapping.segment == self.current {
+            self.used = 0;
+        } else {
+            self.free.push(mapping.segment);
+        }
+    }
+}
+
+// A value held in the mapped file. Clones share the same bytes, which are
+// released once the last clone is dropped.
+#[derive(Clone)]
+pub struct MappedStr(Arc<Mapping>);
+
+struct Mapping {
+    ptr: *const u8,
+    len: usize,
+    segment: usize,
+}
+
+// The bytes are never written while a Mapping refers to them, and
+// segments are never unmapped
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if let Some(store) = STORE.get() {
+            store.lock().unwrap().release(self);
+        }
+    }
+}
+
+impl Deref for MappedStr {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        // Copied from a &str, so the bytes are valid UTF-8
+        unsafe { std::str::from_utf8_unchecked(std::slice::from_raw_parts(self.0.ptr, self.0.len)) }
+    }
+}
+
+impl PartialEq for MappedStr {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl fmt::Debug for MappedStr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MappedStr({} bytes)", self.0.len)
+    }
+}
+
+impl fmt::Display for MappedStr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self)
+    }
+}
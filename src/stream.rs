@@ -0,0 +1,322 @@
+// Append-only streams of field/value entries in ID order, for XADD and
+// XRANGE, with consumer groups for XREADGROUP/XACK. IDs are "<ms>-<seq>"
+// as in Redis. Serialized as "STRM" followed by the entries and groups as
+// JSON, since WAL values must be strings.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+const HEADER: &str = "STRM";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId { ms: u64::MAX, seq: u64::MAX };
+
+    // Parse "ms-seq"; a bare "ms" takes default_seq
+    pub fn parse(s: &str, default_seq: u64) -> Option<StreamId> {
+        let (ms, seq) = match s.split_once('-') {
+            Some((ms, seq)) => (ms.parse().ok()?, seq.parse().ok()?),
+            None => (s.parse().ok()?, default_seq),
+        };
+        Some(StreamId { ms, seq })
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StreamEntry {
+    pub id: StreamId,
+    pub fields: Vec<(String, String)>,
+}
+
+// Delivery state of one consumer group: the newest ID handed out and
+// the entries delivered but not yet acknowledged, in ID order
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ConsumerGroup {
+    last_delivered: StreamId,
+    pending: Vec<PendingEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct PendingEntry {
+    id: StreamId,
+    consumer: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Stream {
+    entries: Vec<StreamEntry>,
+    // Absent in streams encoded before consumer groups existed
+    #[serde(default)]
+    groups: BTreeMap<String, ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // ID of the newest entry, or 0-0 for an empty stream
+    pub fn last_id(&self) -> StreamId {
+        self.entries.last().map_or(StreamId::MIN, |entry| entry.id)
+    }
+
+    // Auto-generated ID for the next entry: the current time, or one past
+    // the last ID if the clock has not moved beyond it. None once the last
+    // ID is StreamId::MAX.
+    pub fn next_id(&self, now_ms: u64) -> Option<StreamId> {
+        let last = self.last_id();
+        if now_ms > last.ms {
+            Some(StreamId { ms: now_ms, seq: 0 })
+        } else if last.seq < u64::MAX {
+            Some(StreamId { ms: last.ms, seq: last.seq + 1 })
+        } else {
+            Some(StreamId { ms: last.ms.checked_add(1)?, seq: 0 })
+        }
+    }
+
+    // Append an entry; callers check that id is greater than last_id()
+    pub fn append(&mut self, id: StreamId, fields: Vec<(String, String)>) {
+        self.entries.push(StreamEntry { id, fields });
+    }
+
+    // Entries with IDs in the inclusive range [start, end]
+    pub fn range(&self, start: StreamId, end: StreamId) -> &[StreamEntry] {
+        let from = self.entries.partition_point(|entry| entry.id < start);
+        let to = self.entries.partition_point(|entry| entry.id <= end);
+        &self.entries[from..to.max(from)]
+    }
+
+    // Entries with IDs greater than id, at most count of them
+    pub fn after(&self, id: StreamId, count: Option<usize>) -> &[StreamEntry] {
+        let from = self.entries.partition_point(|entry| entry.id <= id);
+        let to = count.map_or(self.entries.len(), |n| from.saturating_add(n).min(self.entries.len()));
+        &self.entries[from..to]
+    }
+
+    pub fn has_group(&self, group: &str) -> bool {
+        self.groups.contains_key(group)
+    }
+
+    // Create a group that will deliver entries after last_delivered;
+    // callers check that the name is free
+    pub fn create_group(&mut self, group: String, last_delivered: StreamId) {
+        self.groups.insert(group, ConsumerGroup { last_delivered, pending: Vec::new() });
+    }
+
+    // Entries the group has not delivered yet, at most count of them
+    pub fn undelivered(&self, group: &str, count: Option<usize>) -> &[StreamEntry] {
+        match self.groups.get(group) {
+            Some(g) => self.after(g.last_delivered, count),
+            None => &[],
+        }
+    }
+
+    // Hand the group's next undelivered entries to consumer, recording
+    // them as pending until acknowledged
+    pub fn deliver(&mut self, group: &str, consumer: &str, count: Option<usize>) -> Vec<StreamEntry> {
+        let entries = self.undelivered(group, count).to_vec();
+        if let (Some(g), Some(last)) = (self.groups.get_mut(group), entries.last()) {
+            g.last_delivered = last.id;
+            g.pending.extend(entries.iter().map(|entry| PendingEntry {
+                id: entry.id,
+                consumer: consumer.to_string(),
+            }));
+        }
+        entries
+    }
+
+    // Entries delivered to consumer and not yet acknowledged, with IDs
+    // greater than id
+    pub fn pending_for(&self, group: &str, consumer: &str, id: StreamId) -> Vec<&StreamEntry> {
+        let Some(g) = self.groups.get(group) else {
+            return Vec::new();
+        };
+        g.pending
+            .iter()
+            .filter(|p| p.consumer == consumer && p.id > id)
+            .filter_map(|p| {
+                let index = self.entries.binary_search_by_key(&p.id, |entry| entry.id).ok()?;
+                Some(&self.entries[index])
+            })
+            .collect()
+    }
+
+    pub fn is_pending(&self, group: &str, id: StreamId) -> bool {
+        self.groups
+            .get(group)
+            .is_some_and(|g| g.pending.iter().any(|p| p.id == id))
+    }
+
+    // Acknowledge entries for the group; returns how many were pending
+    pub fn ack(&mut self, group: &str, ids: &[StreamId]) -> usize {
+        let Some(g) = self.groups.get_mut(group) else {
+            return 0;
+        };
+        let before = g.pending.len();
+        g.pending.retain(|p| !ids.contains(&p.id));
+        before - g.pending.len()
+    }
+
+    // Rough heap size in bytes: a fixed ID per entry plus field text, and
+    // an ID plus consumer name per pending delivery
+    pub fn size(&self) -> usize {
+        let entries: usize = self
+            .entries
+            .iter()
+            .map(|entry| {
+                16 + entry.fields.iter().map(|(f, v)| f.len() + v.len()).sum::<usize>()
+            })
+            .sum();
+        let groups: usize = self
+            .groups
+            .iter()
+            .map(|(name, g)| {
+                name.len() + 16 + g.pending.iter().map(|p| 16 + p.consumer.len()).sum::<usize>()
+            })
+            .sum();
+        entries + groups
+    }
+
+    pub fn encode(&self) -> String {
+        // Only strings and integers, with string map keys, so this cannot fail
+        let json = serde_json::to_string(self).expect("stream serializes");
+        format!("{}{}", HEADER, json)
+    }
+
+    // Parse an encoded stream; None if the string is not one
+    pub fn decode(s: &str) -> Option<Stream> {
+        serde_json::from_str(s.strip_prefix(HEADER)?).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    fn fields(n: u64) -> Vec<(String, String)> {
+        vec![("n".to_string(), n.to_string())]
+    }
+
+    // Stream holding entries with the given IDs
+    fn stream(ids: &[StreamId]) -> Stream {
+        let mut stream = Stream::default();
+        for (n, &id) in ids.iter().enumerate() {
+            stream.append(id, fields(n as u64));
+        }
+        stream
+    }
+
+    fn ids(entries: &[StreamEntry]) -> Vec<StreamId> {
+        entries.iter().map(|entry| entry.id).collect()
+    }
+
+    #[test]
+    fn parse_ids() {
+        assert_eq!(StreamId::parse("5-3", 0), Some(id(5, 3)));
+        assert_eq!(StreamId::parse("5", 0), Some(id(5, 0)));
+        assert_eq!(StreamId::parse("5", u64::MAX), Some(id(5, u64::MAX)));
+        assert_eq!(StreamId::parse("x-1", 0), None);
+        assert_eq!(StreamId::parse("1-", 0), None);
+        assert_eq!(StreamId::parse("-1", 0), None);
+        assert_eq!(id(5, 3).to_string(), "5-3");
+    }
+
+    #[test]
+    fn next_id_uses_the_clock_when_it_is_ahead() {
+        assert_eq!(Stream::default().next_id(100), Some(id(100, 0)));
+        assert_eq!(stream(&[id(50, 7)]).next_id(100), Some(id(100, 0)));
+    }
+
+    #[test]
+    fn next_id_stays_monotonic_when_the_clock_is_behind() {
+        // Same millisecond, then a clock that went backwards
+        assert_eq!(stream(&[id(100, 0)]).next_id(100), Some(id(100, 1)));
+        assert_eq!(stream(&[id(100, 4)]).next_id(90), Some(id(100, 5)));
+        // A full sequence rolls over into the next millisecond
+        assert_eq!(stream(&[id(100, u64::MAX)]).next_id(90), Some(id(101, 0)));
+        // Nothing comes after the largest ID
+        assert_eq!(stream(&[StreamId::MAX]).next_id(90), None);
+    }
+
+    #[test]
+    fn generated_ids_always_increase() {
+        let mut stream = Stream::default();
+        for now in [10, 10, 10, 5, 11, 11, 0, 20] {
+            let next = stream.next_id(now).unwrap();
+            assert!(next > stream.last_id());
+            stream.append(next, fields(now));
+        }
+        assert_eq!(stream.len(), 8);
+        assert_eq!(stream.last_id(), id(20, 0));
+    }
+
+    #[test]
+    fn range_bounds_are_inclusive() {
+        let s = stream(&[id(1, 0), id(2, 0), id(2, 1), id(3, 0)]);
+        assert_eq!(ids(s.range(StreamId::MIN, StreamId::MAX)), ids(s.range(id(1, 0), id(3, 0))));
+        assert_eq!(ids(s.range(id(2, 0), id(2, 1))), vec![id(2, 0), id(2, 1)]);
+        // Bounds between entries
+        assert_eq!(ids(s.range(id(1, 1), id(2, 0))), vec![id(2, 0)]);
+        // "2" as a start means 2-0 and as an end 2-MAX
+        let start = StreamId::parse("2", 0).unwrap();
+        let end = StreamId::parse("2", u64::MAX).unwrap();
+        assert_eq!(ids(s.range(start, end)), vec![id(2, 0), id(2, 1)]);
+        // Empty and reversed ranges
+        assert!(s.range(id(4, 0), StreamId::MAX).is_empty());
+        assert!(s.range(id(3, 0), id(1, 0)).is_empty());
+        assert!(Stream::default().range(StreamId::MIN, StreamId::MAX).is_empty());
+    }
+
+    #[test]
+    fn after_is_exclusive_and_honours_count() {
+        let s = stream(&[id(1, 0), id(2, 0), id(3, 0)]);
+        assert_eq!(ids(s.after(id(1, 0), None)), vec![id(2, 0), id(3, 0)]);
+        assert_eq!(ids(s.after(StreamId::MIN, Some(2))), vec![id(1, 0), id(2, 0)]);
+        assert!(s.after(id(3, 0), None).is_empty());
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let mut s = stream(&[id(1, 0), id(1, 1)]);
+        s.create_group("g".to_string(), StreamId::MIN);
+        s.deliver("g", "alice", Some(1));
+
+        let encoded = s.encode();
+        assert!(encoded.starts_with(HEADER));
+        assert_eq!(Stream::decode(&encoded), Some(s));
+        assert_eq!(Stream::decode(&Stream::default().encode()), Some(Stream::default()));
+    }
+
+    #[test]
+    fn decode_accepts_streams_without_groups() {
+        let old = r#"STRM{"entries":[{"id":{"ms":1,"seq":0},"fields":[["f","v"]]}]}"#;
+        let s = Stream::decode(old).unwrap();
+        assert_eq!(s.len(), 1);
+        assert!(!s.has_group("g"));
+    }
+
+    #[test]
+    fn decode_rejects_malformed_payloads() {
+        assert_eq!(Stream::decode(r#"{"entries":[]}"#), None);
+        assert_eq!(Stream::decode("STRM"), None);
+        assert_eq!(Stream::decode("STRM{"), None);
+        assert_eq!(Stream::decode(r#"STRM{"entries":5}"#), None);
+    }
+}
@@ -0,0 +1,118 @@
+// Key tracking for client-side caching (CLIENT TRACKING). Connections with
+// tracking on register here; the keys they read are remembered, and a
+// write to one of those keys queues an invalidation for each reader. As in
+// Redis, a key is forgotten once invalidated until the client reads it
+// again. Each connection sends its queued invalidations itself, between
+// commands, so they never land inside a reply.
+//
+// Writers note the keys they log and publish them only once the command
+// has been applied, so a client that re-reads on invalidation never sees
+// the old value.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+static REGISTRY: Mutex<Option<Registry>> = Mutex::new(None);
+
+// Registered trackers; publishing is skipped while this is 0. Checked
+// after a write is applied, so a tracker registered later reads the new
+// value and has nothing to be told.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // Keys written by the command this thread is running
+    static WRITTEN: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    // Invalidated keys waiting to be sent, per tracking connection
+    pending: HashMap<u64, Arc<Mutex<Vec<String>>>>,
+    // Tracking connections that have read each key
+    readers: HashMap<String, HashSet<u64>>,
+}
+
+// A connection's tracking registration; dropping it turns tracking off
+pub struct Tracker {
+    id: u64,
+    pending: Arc<Mutex<Vec<String>>>,
+}
+
+impl Tracker {
+    pub fn register() -> Tracker {
+        let mut registry = REGISTRY.lock().unwrap();
+        let registry = registry.get_or_insert_with(Registry::default);
+        let id = registry.next_id;
+        registry.next_id += 1;
+        let pending = Arc::new(Mutex::new(Vec::new()));
+        registry.pending.insert(id, Arc::clone(&pending));
+        ACTIVE.fetch_add(1, Ordering::SeqCst);
+        Tracker { id, pending }
+    }
+
+    // Remember that this connection read keys
+    pub fn track<'a>(&self, keys: impl IntoIterator<Item = &'a str>) {
+        let mut registry = REGISTRY.lock().unwrap();
+        let Some(registry) = registry.as_mut() else {
+            return;
+        };
+        for key in keys {
+            registry.readers.entry(key.to_string()).or_default().insert(self.id);
+        }
+    }
+
+    // Take the keys invalidated since the last call
+    pub fn take_invalidations(&self) -> Vec<String> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        let mut registry = REGISTRY.lock().unwrap();
+        if let Some(registry) = registry.as_mut() {
+            registry.pending.remove(&self.id);
+            registry.readers.retain(|_, readers| {
+                readers.remove(&self.id);
+                !readers.is_empty()
+            });
+        }
+        ACTIVE.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Record that the current command wrote key
+pub fn note_written(key: &str) {
+    WRITTEN.with_borrow_mut(|written| written.push(key.to_string()));
+}
+
+// Invalidate the keys noted since the last call; run once the writes are
+// applied to the map
+pub fn publish_written() {
+    let written = WRITTEN.take();
+    if ACTIVE.load(Ordering::SeqCst) == 0 {
+        return;
+    }
+    for key in written {
+        invalidate(&key);
+    }
+}
+
+// Queue an invalidation of key for every tracking connection that read it
+fn invalidate(key: &str) {
+    let mut registry = REGISTRY.lock().unwrap();
+    let Some(registry) = registry.as_mut() else {
+        return;
+    };
+    let Some(readers) = registry.readers.remove(key) else {
+        return;
+    };
+    for id in readers {
+        if let Some(pending) = registry.pending.get(&id) {
+            pending.lock().unwrap().push(key.to_string());
+        }
+    }
+}